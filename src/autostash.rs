@@ -0,0 +1,91 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// --autostash: put the local changes away before a poset rebase, and back after it
+// (or after --abort).  The stash is an ordinary entry of the stash list, so if it
+// cannot be re-applied the user still finds it there.
+
+use git2::{ErrorCode, Oid, Repository, StashApplyOptions, StashFlags};
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::base::conflicted_paths;
+use crate::rebase::RebaseError;
+
+const AUTOSTASH_CONFIG: &str = "hierarchy.autostash";
+const AUTOSTASH_UNTRACKED_CONFIG: &str = "hierarchy.autostashUntracked";
+const AUTOSTASH_MESSAGE: &str = "git-rebase-poset: autostash";
+
+/// The `hierarchy.autostash' and `hierarchy.autostashUntracked' git config, default false.
+pub fn autostash_config(repository: &Repository) -> Result<(bool, bool), RebaseError> {
+    let config = repository.config()?;
+    let get = |name| match config.get_bool(name) {
+        Ok(value) => Ok(value),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(e),
+    };
+    Ok((get(AUTOSTASH_CONFIG)?, get(AUTOSTASH_UNTRACKED_CONFIG)?))
+}
+
+// stashing needs a &mut Repository, and we are passed & ones all around.
+fn reopen(repository: &Repository) -> Result<Repository, git2::Error> {
+    Repository::open(repository.path())
+}
+
+/// Stash the tracked changes, and the untracked files too if @untracked.
+/// None if there's nothing to stash.
+pub fn stash(repository: &Repository, untracked: bool) -> Result<Option<Oid>, RebaseError> {
+    let mut flags = StashFlags::DEFAULT;
+    if untracked {
+        flags |= StashFlags::INCLUDE_UNTRACKED;
+    }
+
+    let signature = repository.signature()?;
+    match reopen(repository)?.stash_save(&signature, AUTOSTASH_MESSAGE, Some(flags)) {
+        Ok(oid) => {
+            info!("autostash: {}", oid);
+            Ok(Some(oid))
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            debug!("nothing to stash");
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Re-apply the stash @oid, and drop it from the stash list.
+/// When it conflicts, it stays in the list.
+pub fn unstash(repository: &Repository, oid: Oid) -> Result<(), RebaseError> {
+    let mut stash_repository = reopen(repository)?;
+
+    let mut position = None;
+    stash_repository.stash_foreach(|index, _message, stash_oid| {
+        if *stash_oid == oid {
+            position = Some(index);
+        }
+        position.is_none()
+    })?;
+    let Some(position) = position else {
+        warn!("the autostash {} is not in the stash list anymore", oid);
+        return Ok(());
+    };
+
+    let mut apply_opts = StashApplyOptions::new();
+    apply_opts.reinstantiate_index();
+    if let Err(e) = stash_repository.stash_apply(position, Some(&mut apply_opts)) {
+        if e.code() == ErrorCode::Conflict || e.code() == ErrorCode::MergeConflict {
+            return Err(RebaseError::AutostashConflict { stash: oid, paths: Vec::new(), source: Some(e) });
+        }
+        return Err(e.into());
+    }
+
+    let index = stash_repository.index()?;
+    if index.has_conflicts() {
+        return Err(RebaseError::AutostashConflict { stash: oid, paths: conflicted_paths(&index)?, source: None });
+    }
+
+    info!("autostash {} applied", oid);
+    stash_repository.stash_drop(position)?;
+    Ok(())
+}
@@ -3,7 +3,7 @@
 
 use crate::utils::concatenate;
 use git2::{Branch, Commit, Oid,
-           Error,
+           Error, Index,
            Reference, Repository, build::CheckoutBuilder,
            Sort,
            StatusShow,StatusOptions, Statuses,};
@@ -99,6 +99,37 @@ pub fn checkout_new_head_at<'repo>(
     }
 }
 
+/// Checkout @commit on a detached HEAD, keeping local changes (fails if they'd be overwritten).
+pub fn checkout_detached(repository: &Repository, commit: &Commit<'_>) -> Result<(), Error> {
+    let mut checkout_opts = CheckoutBuilder::new();
+    checkout_opts.safe();
+    repository.checkout_tree(commit.as_object(), Some(&mut checkout_opts))?;
+    repository.set_head_detached(commit.id())
+}
+
+/// If HEAD is the branch @reference, detach it at the same commit.
+/// So that we can move the branch without the checkout getting out of sync.
+pub fn detach_head_from(repository: &Repository, reference: &Reference<'_>) -> Result<(), Error> {
+    let head = repository.head()?;
+    if head.is_branch() && head.name() == reference.name() {
+        info!("detaching HEAD from {}", reference.name().unwrap());
+        repository.set_head_detached(head.target().unwrap())?;
+    }
+    Ok(())
+}
+
+/// Paths of the conflicting entries in the @index.
+pub fn conflicted_paths(index: &Index) -> Result<Vec<String>, Error> {
+    let mut paths = Vec::new();
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+            paths.push(String::from_utf8_lossy(&entry.path).into_owned());
+        }
+    }
+    Ok(paths)
+}
+
 // get the status: list of file modified in Index
 pub fn staged_files<'repo>(repository: &'repo Repository) -> Result<Statuses<'repo>, Error>{
     let mut status_options = StatusOptions::new();
@@ -0,0 +1,183 @@
+//
+// operations on a whole hierarchy, which are neither rebasing nor walking it.
+
+use clap::{Parser, Subcommand};
+use git2::Repository;
+
+use colored::Colorize;
+
+use std::path::PathBuf;
+use std::process::exit;
+
+use git_hierarchy::utils::{extract_name, init_tracing};
+use git_hierarchy::base::open_repository;
+use git_hierarchy::graph::discover_pet::find_hierarchy;
+use git_hierarchy::hierarchy_file::{HierarchyFile, apply, export};
+use git_hierarchy::push::{hierarchy_leases, push_with_lease};
+use git_hierarchy::rebase::RebaseError;
+use git_hierarchy::share::{fetch_definitions, publish, reconcile};
+
+#[allow(unused)]
+use tracing::{debug, info};
+
+
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    #[arg(long, short='g', global=true)]
+    directory: Option<PathBuf>,
+
+    #[arg(short, long, action = clap::ArgAction::Count, global=true)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Push the segments & sums to their upstreams, unless somebody pushed there since the last fetch
+    Push(RootArgs),
+    /// Push the definitions of all the segments & sums to refs/hierarchy/ on the remote, unless they differ there
+    Publish(RemoteArgs),
+    /// Fetch the published definitions, and create the segments & sums missing here
+    FetchDefinitions(RemoteArgs),
+    /// Print the segments & sums reachable from the root as TOML
+    Export(RootArgs),
+    /// Create & update the segments & sums to match the TOML file
+    Apply(ApplyArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RootArgs {
+    /// Default: the current branch
+    root: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RemoteArgs {
+    remote: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct ApplyArgs {
+    file: PathBuf,
+}
+
+fn root_name(repository: &Repository, root: Option<&String>) -> Result<String, RebaseError> {
+    let reference = match root {
+        Some(name) => repository.resolve_reference_from_short_name(name)?,
+        None => repository.head()?,
+    };
+    Ok(reference.name().unwrap().to_owned())
+}
+
+fn push(repository: &Repository, args: &RootArgs) -> Result<(), RebaseError> {
+    let root = root_name(repository, args.root.as_ref())?;
+    let hierarchy_graph = find_hierarchy(repository, root);
+
+    let mut refused = Vec::new();
+    // in the order, base first.
+    let nodes = hierarchy_graph.discovery_order.iter()
+        .map(|v| hierarchy_graph.labeled_objects.get(v).unwrap());
+    for lease in hierarchy_leases(repository, nodes)? {
+        let status = push_with_lease(repository, &lease)?;
+        println!("{}: {}", extract_name(&lease.branch), status);
+        if status.failed() {
+            refused.push(extract_name(&lease.branch).to_owned());
+        }
+    }
+
+    if refused.is_empty() {
+        Ok(())
+    } else {
+        Err(RebaseError::PushRefused(refused))
+    }
+}
+
+fn publish_definitions(repository: &Repository, args: &RemoteArgs) -> Result<(), RebaseError> {
+    let mut conflicts = Vec::new();
+    for (name, published) in publish(repository, &args.remote)? {
+        println!("{}: {}", name, published);
+        if published.conflict() {
+            conflicts.push(name);
+        }
+    }
+
+    if conflicts.is_empty() {
+        println!("published to {}", args.remote);
+        Ok(())
+    } else {
+        println!("nothing published to {}", args.remote);
+        Err(RebaseError::DefinitionConflict(conflicts))
+    }
+}
+
+fn fetch_remote_definitions(repository: &Repository, args: &RemoteArgs) -> Result<(), RebaseError> {
+    let mut conflicts = Vec::new();
+    for definition in fetch_definitions(repository, &args.remote)? {
+        let reconciled = reconcile(repository, &definition)?;
+        println!("{}: {}", definition.name(), reconciled);
+        if reconciled.conflict() {
+            conflicts.push(definition.name().to_owned());
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(RebaseError::DefinitionConflict(conflicts))
+    }
+}
+
+fn export_hierarchy(repository: &Repository, args: &RootArgs) -> Result<(), RebaseError> {
+    let root = root_name(repository, args.root.as_ref())?;
+    let hierarchy_graph = find_hierarchy(repository, root);
+
+    let nodes = hierarchy_graph.discovery_order.iter()
+        .map(|v| hierarchy_graph.labeled_objects.get(v).unwrap());
+    print!("{}", export(repository, nodes)?.to_toml());
+    Ok(())
+}
+
+fn apply_file(repository: &Repository, args: &ApplyArgs) -> Result<(), RebaseError> {
+    let text = std::fs::read_to_string(&args.file)?;
+    let file = HierarchyFile::from_toml(&text)
+        .map_err(|e| RebaseError::InvalidFile { path: args.file.clone(), reason: e.to_string() })?;
+
+    let mut conflicts = Vec::new();
+    for (name, changes) in apply(repository, &file)? {
+        for change in &changes {
+            println!("{}: {}", name, change);
+        }
+        if changes.iter().any(|c| c.conflict()) {
+            conflicts.push(name);
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(RebaseError::DefinitionConflict(conflicts))
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    init_tracing(cli.verbose);
+
+    let repository = open_repository(cli.directory.as_ref()).expect("should find the Git directory");
+
+    let result = match &cli.command {
+        Commands::Push(args) => push(&repository, args),
+        Commands::Publish(args) => publish_definitions(&repository, args),
+        Commands::FetchDefinitions(args) => fetch_remote_definitions(&repository, args),
+        Commands::Export(args) => export_hierarchy(&repository, args),
+        Commands::Apply(args) => apply_file(&repository, args),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}: {}", "Failed".red(), e);
+        exit(1);
+    }
+}
@@ -5,6 +5,8 @@ use clap::Parser;
 
 use git_hierarchy::git_hierarchy::{GitHierarchy};
 use git_hierarchy::rebase::{check_segment, rebase_segment};
+use git_hierarchy::rebase_state::{EmptyPolicy, RebaseOptions, RebaseState};
+use git_hierarchy::snapshot::RefSnapshot;
 use git_hierarchy::utils::{init_tracing};
 use git_hierarchy::base::open_repository;
 
@@ -16,6 +18,12 @@ struct Cli {
 
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
+
+    /// What to do with commits becoming empty: drop, keep or stop.
+    /// Default: the `hierarchy.empty' git config, or stop
+    #[arg(long, value_name = "POLICY")]
+    empty: Option<EmptyPolicy>,
+
     // todo: continue -> use git-rebase-poset -c
     // should this be an invocation of git-rebase-poset?
     segment_name: String,
@@ -32,11 +40,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>>{
         Err(e) => panic!("failed to open: {}", e),
     };
 
-    // continue...
+    if RebaseState::exists(&repository) {
+        return Err("rebase underway, use git-rebase-poset --continue or --abort".into());
+    }
+
     let gh = git_hierarchy::git_hierarchy::load(&repository, &cli.segment_name).unwrap();
-    if let GitHierarchy::Segment(segment) = gh {
-        check_segment(&repository, &segment)?;
-        rebase_segment(&repository, &segment).unwrap();
+    if let GitHierarchy::Segment(segment) = &gh {
+        check_segment(&repository, segment)?;
+
+        // on conflict git-rebase-poset -c continues with just this segment.
+        let empty = match cli.empty {
+            Some(empty) => empty,
+            None => EmptyPolicy::from_config(&repository)?.unwrap_or_default(),
+        };
+        let mut state = RebaseState::new(segment.name().to_owned(),
+                                         vec![segment.name().to_owned()],
+                                         RebaseOptions { empty, ..Default::default() },
+                                         RefSnapshot::record(&repository, std::iter::once(&gh))?);
+        state.save(&repository)?;
+        // on failure the state stays, for --continue/--abort
+        rebase_segment(&repository, segment, &mut state)?;
+        state.original.return_to_head(&repository)?;
+        for dropped in &state.dropped {
+            eprintln!("dropped {}: {}", dropped.oid, dropped.summary);
+        }
+        RebaseState::remove(&repository)?;
     }
     Ok(())
 }
@@ -1,11 +1,15 @@
 use std::path::PathBuf;
+use std::process::exit;
 use clap::{Parser,Subcommand,CommandFactory,FromArgMatches};
-use git2::{Repository,Oid}; // ,build::CheckoutBuilder
+use git2::{Reference,Repository,Oid}; // ,build::CheckoutBuilder
 
 #[allow(unused_imports)]
 use git_hierarchy::git_hierarchy::{GitHierarchy,Segment,segments,load,
-                                   segment_fmt,
+                                   segment_fmt, sum_fmt,
 };
+use git_hierarchy::dependents::{Dependent,ReverseIndex,redirect,rewire};
+use git_hierarchy::describe::{Listing,describe_by_name};
+use colored::Colorize;
 
 use tracing::debug;
 
@@ -24,6 +28,10 @@ struct Cli {
     #[command(flatten)]
     git_repository: ClapGitRepo,
 
+    /// Print the listing/description as JSON, see the readme
+    #[arg(long, global=true)]
+    json: bool,
+
     #[command(subcommand)]
     #[command(name="subcommand")]
     // expand shows:
@@ -64,6 +72,10 @@ enum Commands {
     #[command(name="create", version, long_about = None,long_flag("create"),short_flag('c'))]
     /// Create a new segment, and checkout it
     Create(DefineArgs),
+    /// Split off the commits up to one, as a new segment below
+    Split(SplitArgs),
+    /// Merge a segment into the one based on it
+    Join(JoinArgs),
 }
 
 
@@ -109,7 +121,29 @@ struct RebaseArgs {
 #[derive(clap::Args)]
 #[command(version, about, long_about = None,long_flag("delete"),short_flag('d'))]
 struct DeleteCmd {
+    /// Re-base the segments based on it onto its base, and drop it from sums
+    #[arg(long)]
+    rewire: bool,
+
+    segment_name: String,
+}
+
+#[derive(clap::Args)]
+#[command(version, about, long_about = None,long_flag("split"))]
+struct SplitArgs {
     segment_name: String,
+    /// the last commit of the new segment
+    commit: String,
+    new_name: String,
+}
+
+#[derive(clap::Args)]
+#[command(version, about, long_about = None,long_flag("join"))]
+struct JoinArgs {
+    /// deleted
+    lower: String,
+    /// based on the lower, keeps the name
+    upper: String,
 }
 
 // I want this default.... can I flatten it in?
@@ -138,6 +172,9 @@ fn resolve_user_commit(repository: &Repository, input: &str) -> Option<Oid> {
     } else if let Ok(reference) = repository.resolve_reference_from_short_name(input) {
         // refname_to_id
         Some(reference.target().unwrap())
+    } else if let Ok(commit) = repository.revparse_single(input).and_then(|o| o.peel_to_commit()) {
+        // seg~2 & co.
+        Some(commit.id())
     } else {
         debug!("couldn't find reference {}", input);
         None
@@ -171,10 +208,38 @@ fn define<'repo> (repository: &'repo Repository, args: &DefineArgs) -> Result<Se
 }
 
 fn delete(repository: &Repository, args: &DeleteCmd) {
-    // check sums above
-    // segments based on it.
     let gh = git_hierarchy::git_hierarchy::load(repository, &args.segment_name).unwrap();
     if let GitHierarchy::Segment(mut segment) = gh {
+        // segments based on it, and sums above
+        let index = ReverseIndex::new(repository).expect("should scan the hierarchy refs");
+        let target = segment.reference.borrow().name().unwrap().to_owned();
+        let dependents = index.dependents(&target);
+
+        if !dependents.is_empty() {
+            if !args.rewire {
+                eprintln!("{} {} is used by:", Colorize::red("refusing to delete"), segment_fmt(segment.name()));
+                for dependent in dependents {
+                    match dependent {
+                        Dependent::Segment(name) => eprintln!("\tsegment {}", segment_fmt(name)),
+                        Dependent::Sum(name) => eprintln!("\tsum {}", sum_fmt(name)),
+                    }
+                }
+                eprintln!("use --rewire to re-base/remove them");
+                exit(1);
+            }
+
+            let base = segment.base(repository);
+            if let Err(e) = rewire(repository, &segment.reference.borrow(), Some(&base), dependents) {
+                eprintln!("{}: {}", Colorize::red("cannot rewire"), e.message());
+                exit(1);
+            }
+            for dependent in dependents {
+                if let Dependent::Sum(name) = dependent {
+                    println!("sum {} should be re-merged", sum_fmt(name));
+                }
+            }
+        }
+
         println!("Delete {} in {:?}", args.segment_name, repository.path());
 
         segment.base.borrow_mut().delete().unwrap();
@@ -183,11 +248,146 @@ fn delete(repository: &Repository, args: &DeleteCmd) {
     }
 }
 
+/// base <- new (start..commit) <- segment (commit..head)
+/// The head does not move, so sums using the segment stay valid.
+fn split(repository: &Repository, args: &SplitArgs) {
+    let gh = git_hierarchy::git_hierarchy::load(repository, &args.segment_name).unwrap();
+    let GitHierarchy::Segment(segment) = gh else {
+        eprintln!("{} is not a segment", segment_fmt(&args.segment_name));
+        exit(1);
+    };
+    let Some(oid) = resolve_user_commit(repository, &args.commit) else {
+        eprintln!("cannot find the commit {}", args.commit);
+        exit(1);
+    };
+
+    // oldest first
+    let commits: Vec<Oid> = segment.iter(repository).unwrap().map(|oid| oid.unwrap()).collect();
+    let Some(position) = commits.iter().position(|c| *c == oid) else {
+        eprintln!("{} is not in the segment {}", oid, segment_fmt(segment.name()));
+        exit(1);
+    };
+    if position + 1 == commits.len() {
+        eprintln!("{} is the head of {}, nothing would be left in it", oid, segment_fmt(segment.name()));
+        exit(1);
+    }
+
+    let new_ref = format!("refs/heads/{}", args.new_name);
+    if !Reference::is_valid_name(&new_ref) {
+        eprintln!("{} is not a valid branch name", args.new_name);
+        exit(1);
+    }
+    let new_refs = [new_ref, format!("refs/base/{}", args.new_name), format!("refs/start/{}", args.new_name)];
+    if let Some(taken) = new_refs.iter().find(|name| repository.find_reference(name).is_ok()) {
+        eprintln!("{} is taken: {} exists", args.new_name, taken);
+        exit(1);
+    }
+
+    let base = segment.base(repository);
+    let lower = match Segment::create(repository, &args.new_name, &base, segment.start(), oid) {
+        Ok(lower) => lower,
+        Err(e) => {
+            eprintln!("failed to define the segment {}: {}", segment_fmt(&args.new_name), e.message());
+            exit(1);
+        }
+    };
+    if let Err(e) = segment.move_to(repository, &lower.reference.borrow(), oid) {
+        eprintln!("failed to move {} on top of {}: {}", segment_fmt(segment.name()), segment_fmt(lower.name()),
+                  e.message());
+        // so that the two do not overlap.
+        for name in &new_refs {
+            if let Err(e) = repository.find_reference(name).and_then(|mut r| r.delete()) {
+                eprintln!("failed to delete {}: {}", name, e.message());
+            }
+        }
+        exit(1);
+    }
+
+    println!("split {} into {} ({} commits) and {} ({} commits)",
+             segment_fmt(segment.name()),
+             segment_fmt(lower.name()), position + 1,
+             segment_fmt(segment.name()), commits.len() - position - 1);
+}
+
+fn load_segment<'repo>(repository: &'repo Repository, name: &str) -> Segment<'repo> {
+    match git_hierarchy::git_hierarchy::load(repository, name) {
+        Ok(GitHierarchy::Segment(segment)) => segment,
+        _ => {
+            eprintln!("{} is not a segment", segment_fmt(name));
+            exit(1);
+        }
+    }
+}
+
+/// base <- lower <- upper  =>  base <- upper (lower's start..upper's head)
+/// What used the lower, uses the joined one.
+fn join(repository: &Repository, args: &JoinArgs) {
+    let mut lower = load_segment(repository, &args.lower);
+    let upper = load_segment(repository, &args.upper);
+    let lower_name = lower.reference.borrow().name().unwrap().to_owned();
+
+    if upper.base(repository).name() != Some(lower_name.as_str()) {
+        eprintln!("{} is not based on {}", segment_fmt(upper.name()), segment_fmt(lower.name()));
+        exit(1);
+    }
+    // otherwise the lower's newer commits would be lost.
+    if upper.start() != lower.reference.borrow().target().unwrap() {
+        eprintln!("{} is not on top of {}, rebase it first", segment_fmt(upper.name()), segment_fmt(lower.name()));
+        exit(1);
+    }
+    if repository.head().ok().and_then(|h| h.name().map(str::to_owned)) == Some(lower_name.clone()) {
+        eprintln!("{} is checked out", segment_fmt(lower.name()));
+        exit(1);
+    }
+
+    let index = ReverseIndex::new(repository).expect("should scan the hierarchy refs");
+    let others: Vec<Dependent> = index.dependents(&lower_name).iter()
+        .filter(|d| **d != Dependent::Segment(upper.name().to_owned()))
+        .cloned()
+        .collect();
+    let lower_base = lower.base(repository);
+    if let Err(e) = upper.move_to(repository, &lower_base, lower.start()) {
+        eprintln!("failed to move {} onto {}: {}", segment_fmt(upper.name()), lower_base.name().unwrap(), e.message());
+        exit(1);
+    }
+    if let Err(e) = redirect(repository, &lower.reference.borrow(), &upper.reference.borrow(), &others) {
+        eprintln!("{}: {}", Colorize::red("cannot rewire"), e.message());
+        // back on top of the lower, which stays.
+        if let Err(e) = upper.move_to(repository, &lower.reference.borrow(), lower.reference.borrow().target().unwrap()) {
+            eprintln!("failed to move {} back onto {}: {}", segment_fmt(upper.name()), segment_fmt(lower.name()),
+                      e.message());
+        }
+        exit(1);
+    }
+
+    println!("joined {} into {}", segment_fmt(lower.name()), segment_fmt(upper.name()));
+    for dependent in &others {
+        match dependent {
+            Dependent::Segment(name) => println!("segment {} is based on {} now", segment_fmt(name), segment_fmt(upper.name())),
+            Dependent::Sum(name) => println!("sum {} should be re-merged", sum_fmt(name)),
+        }
+    }
+
+    // only now, nothing uses the lower anymore.
+    let deleted = lower.base.borrow_mut().delete()
+        .and_then(|_| lower._start.delete())
+        .and_then(|_| lower.reference.borrow_mut().delete());
+    if let Err(e) = deleted {
+        eprintln!("failed to delete the segment {}: {}", segment_fmt(lower.name()), e.message());
+        exit(1);
+    }
+}
+
 // see list_segment in git-walk-down.rs
-fn describe(repository: &Repository, segment_name: &str) {
+fn describe(repository: &Repository, segment_name: &str, json: bool) {
 
     let gh = git_hierarchy::git_hierarchy::load(repository, segment_name).unwrap();
     if let GitHierarchy::Segment(segment) = gh {
+        if json {
+            let info = describe_by_name(repository, segment.name()).expect("should describe the segment");
+            println!("{}", Listing::new(vec![info]).to_json());
+            return;
+        }
         println!("Segment {} in {:?}", segment_fmt(segment_name), repository.path());
 
         // todo: drop the refs/
@@ -202,14 +402,25 @@ fn describe(repository: &Repository, segment_name: &str) {
             let commit = repository.find_commit(oid).unwrap();
             println!("{}: {}", oid, commit.summary().unwrap());
         }
+    } else if json {
+        eprintln!("Segment {} does not exist", segment_name);
+        exit(1);
     } else {
         println!("Segment {} does not exist", segment_fmt(segment_name));
     }
 }
 
-fn list_segments(repository: &Repository) {
+fn list_segments(repository: &Repository, json: bool) {
     let ref_iterator = segments(repository);
 
+    if json {
+        let nodes = ref_iterator
+            .map(|r| describe_by_name(repository, &r).expect("should describe the segment"))
+            .collect();
+        println!("{}", Listing::new(nodes).to_json());
+        return;
+    }
+
     for r in ref_iterator {
         println!("{}", segment_fmt(&r));
     }
@@ -260,7 +471,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     if let Some(command) = clip.command {
         match command {
             Commands::List(_args) => {
-                list_segments(&repository);
+                list_segments(&repository, clip.json);
             }
             Commands::Restart(args) => {
                 let gh = git_hierarchy::git_hierarchy::load(&repository, &args.segment_name).unwrap();
@@ -300,12 +511,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             Commands::Define(args) => {
                 define(&repository, &args).expect("failed to define new segment");
             },
+            Commands::Split(args) => {
+                split(&repository, &args);
+            },
+            Commands::Join(args) => {
+                join(&repository, &args);
+            },
         }
     } else if let Some(args) = clip.define_or_show_args {
         if args.is_empty() {
             unreachable!("cannot be Some, and empty vector");
         } else if args.len() == 1 {
-            describe(&repository, &args[0]);
+            describe(&repository, &args[0], clip.json);
         } else {
             // convert....
             let def = DefineArgs {
@@ -320,7 +537,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             define(&repository, &def).unwrap();
         }
     } else {
-        list_segments(&repository);
+        list_segments(&repository, clip.json);
     }
     // else nothing. Or list?
     // return Err(error.into());
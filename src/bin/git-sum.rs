@@ -5,7 +5,10 @@ use git2::{Repository,Reference,Oid};
 use colored::Colorize;
 
 #[allow(unused_imports)]
-use git_hierarchy::git_hierarchy::{GitHierarchy,Sum,load,sums, sum_fmt};
+use git_hierarchy::git_hierarchy::{GitHierarchy,Sum,load,sums, sum_fmt, segment_fmt};
+use git_hierarchy::dependents::{Dependent,ReverseIndex,rewire};
+use git_hierarchy::describe::{Listing,describe_by_name};
+use git_hierarchy::rebase::commit_sum;
 
 
 #[allow(unused)]
@@ -25,6 +28,10 @@ struct Cli {
     #[command(flatten)]
     git_repository: ClapGitRepo,
 
+    /// Print the listing/description as JSON, see the readme
+    #[arg(long, global=true)]
+    json: bool,
+
     #[command(subcommand)]
     #[command(name="subcommand")]
     command: Option<Commands>,
@@ -60,8 +67,8 @@ enum Commands {
     #[command(name="add", version, about, long_about = None,long_flag("add"),short_flag('a'))]
     Add(AddArgs),
 
-    // #[command(name="remove", version, about, long_about = None,long_flag("remove"),short_flag('r'))]
-    // Remove(RemoveArgs),
+    #[command(name="remove", version, about, long_about = None,long_flag("remove"),short_flag('r'))]
+    Remove(RemoveArgs),
 }
 
 #[derive(clap::Args)]
@@ -75,6 +82,18 @@ struct AddArgs {
     summands: Vec<String>,
 }
 
+/// Drop summands from a sum, renumbering the rest
+#[derive(clap::Args)]
+struct RemoveArgs {
+    /// Rebuild the merge commit from the remaining summands
+    #[arg(long)]
+    remerge: bool,
+
+    name: String,
+    #[arg(required = true)]
+    summands: Vec<String>,
+}
+
 #[derive(clap::Args)]
 // why do I have this, and not #[arg()]?
 struct DefineArgs
@@ -108,6 +127,10 @@ struct ListArgs {
 #[derive(clap::Args)]
 #[command(version, about, long_about = None,long_flag("delete"),short_flag('d'))]
 struct DeleteCmd {
+    /// Drop it from the sums using it
+    #[arg(long)]
+    rewire: bool,
+
     sum_name: String,
 }
 
@@ -154,6 +177,30 @@ fn define_sum<'repo,'a, T: AsRef<str> + 'a>(repository: &'repo Repository,
 fn delete_sum(repository: &Repository, args: &DeleteCmd) {
     let gh = git_hierarchy::git_hierarchy::load(repository, &args.sum_name).unwrap();
     if let GitHierarchy::Sum(sum) = gh {
+        let index = ReverseIndex::new(repository).expect("should scan the hierarchy refs");
+        let target = sum.reference.borrow().name().unwrap().to_owned();
+        let dependents = index.dependents(&target);
+
+        if !dependents.is_empty() {
+            if !args.rewire {
+                eprintln!("{} {} is used by:", Colorize::red("refusing to delete"), sum_fmt(sum.name()));
+                for dependent in dependents {
+                    match dependent {
+                        Dependent::Segment(name) => eprintln!("\tsegment {}", segment_fmt(name)),
+                        Dependent::Sum(name) => eprintln!("\tsum {}", sum_fmt(name)),
+                    }
+                }
+                eprintln!("use --rewire to remove it from the sums");
+                exit(1);
+            }
+
+            // a sum has no base of its own, so segments on it cannot be rewired.
+            if let Err(e) = rewire(repository, &sum.reference.borrow(), None, dependents) {
+                eprintln!("{}: {}", Colorize::red("cannot rewire"), e.message());
+                exit(1);
+            }
+        }
+
         info!("deleting {}", args.sum_name);
         // drop all summands
         sum.reference.borrow_mut().delete().unwrap();
@@ -180,7 +227,7 @@ fn main()
     if let Some(command) = clip.command {
         match command {
             Commands::List(_args) => {
-                list_sums(&repository);
+                list_sums(&repository, clip.json);
             }
             Commands::Define(args) => {
                 define_sum(&repository,
@@ -192,7 +239,7 @@ fn main()
                 delete_sum(&repository, &args);
             }
             Commands::Show(args) => {
-                describe_sum(&repository, &args);
+                describe_sum(&repository, &args, clip.json);
             }
 
             Commands::Add(args) => {
@@ -201,11 +248,14 @@ fn main()
                 // allocate new numbers
                 // create the symbolic refs
             }
+            Commands::Remove(args) => {
+                remove_from_sum(&repository, &args);
+            }
         }
     } else if let Some(args) = clip.define_or_show_args {
         if args.len() == 1 {
             let args = ShowArgs{name: args[0].clone()};
-            describe_sum(&repository, &args);
+            describe_sum(&repository, &args, clip.json);
         } else {
             define_sum(&repository,
                        &args[0],
@@ -214,22 +264,35 @@ fn main()
             // .expect("should not attempt to recreate existing sum");
         }
     } else {
-        list_sums(&repository);
+        list_sums(&repository, clip.json);
     }
 }
 
-fn list_sums(repository: &Repository) {
+fn list_sums(repository: &Repository, json: bool) {
     let ref_iterator = sums(repository);
 
+    if json {
+        let nodes = ref_iterator
+            .map(|r| describe_by_name(repository, &r).expect("should describe the sum"))
+            .collect();
+        println!("{}", Listing::new(nodes).to_json());
+        return;
+    }
+
     for r in ref_iterator {
         println!("{}", r);
     }
 }
 
-fn describe_sum<'repo>(repository: &'repo Repository, args: &ShowArgs) {
-    let gh = git_hierarchy::git_hierarchy::load(&repository, &args.name).unwrap();
+fn describe_sum(repository: &Repository, args: &ShowArgs, json: bool) {
+    let gh = git_hierarchy::git_hierarchy::load(repository, &args.name).unwrap();
     if let GitHierarchy::Sum(sum) = gh {
         //        sum: &git_hierarchy::git_hierarchy::Sum<'repo>
+        if json {
+            let info = describe_by_name(repository, sum.name()).expect("should describe the sum");
+            println!("{}", Listing::new(vec![info]).to_json());
+            return;
+        }
 
         println!("sum {}", sum_fmt(sum.name()));
         let summands = sum.summands(repository);
@@ -240,11 +303,14 @@ fn describe_sum<'repo>(repository: &'repo Repository, args: &ShowArgs) {
 
         // prune non-existings summands ??? why?
         // fn show_prune_definition(){unimplemented!()}
+    } else if json {
+        eprintln!("{} is not a sum", args.name);
+        exit(1);
     }
 }
 
 fn add_to_sum(repository: &Repository, args: &AddArgs) {
-    let gh = git_hierarchy::git_hierarchy::load(&repository, &args.name).unwrap();
+    let gh = git_hierarchy::git_hierarchy::load(repository, &args.name).unwrap();
 
     if let GitHierarchy::Sum(mut sum) = gh {
         let sumrefs : Vec<Reference>
@@ -256,10 +322,42 @@ fn add_to_sum(repository: &Repository, args: &AddArgs) {
     }
 }
 
+fn remove_from_sum(repository: &Repository, args: &RemoveArgs) {
+    let gh = git_hierarchy::git_hierarchy::load(repository, &args.name).unwrap();
 
-/*
-fn git_sum_branches() {unimplemented!()}
+    if let GitHierarchy::Sum(mut sum) = gh {
+        let sumrefs : Vec<Reference>
+            = args.summands.iter().map(|x| {
+                repository.resolve_reference_from_short_name(x.as_ref()).unwrap_or_else(|e| {
+                    eprintln!("{} {}: {}", Colorize::red("cannot find the summand"), x, e.message());
+                    exit(1);
+                })
+            }).collect();
 
+        if let Err(e) = sum.remove_summands(repository, sumrefs.iter()) {
+            eprintln!("{}: {}", Colorize::red("failed to remove summands"), e.message());
+            exit(1);
+        }
 
-fn remove_from_sum() {unimplemented!()}
+        if args.remerge {
+            let summands = sum.summands(repository);
+            let commits : Vec<_> = summands.iter().map(|s| s.peel_to_commit().unwrap()).collect();
+            let names : Vec<&str> = summands.iter().map(|s| s.shorthand().unwrap()).collect();
+
+            match commit_sum(repository, &sum, &commits, &names) {
+                Ok(oid) => info!("re-merged {} at {}", sum.name(), oid),
+                Err(e) => {
+                    eprintln!("{}: {}", Colorize::red("failed to re-merge"), e);
+                    exit(1);
+                }
+            }
+        }
+    } else {
+        eprintln!("{} is not a sum", args.name);
+        exit(1);
+    }
+}
+
+/*
+fn git_sum_branches() {unimplemented!()}
 */
@@ -2,7 +2,7 @@
 // - clone
 // - replaceInHierarchy ...the base from->to, mapping
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use git2::{Repository,Reference};
 
 use colored::Colorize;
@@ -21,6 +21,8 @@ use git_hierarchy::base::{open_repository};
 
 use ::git_hierarchy::graph::discover::NodeExpander;
 use ::git_hierarchy::graph::discover_pet::find_hierarchy;
+use ::git_hierarchy::graph::export::ExportGraph;
+use ::git_hierarchy::describe::{self, Listing};
 
 #[allow(unused)]
 use ::git_hierarchy::git_hierarchy::{GitHierarchy, Segment, Sum, load,
@@ -85,7 +87,7 @@ fn describe_node<'repo>(
             let summands = sum.summands(repository);
 
             println!("sum {}", sum_fmt(sum.name()));
-            if let Err(_) = check_sum(repository, sum, object_map) {
+            if check_sum(repository, sum, object_map).is_err() {
                 println!("{}", "needs update".bright_red().on_white());
             }
 
@@ -271,6 +273,15 @@ where
     }
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// indented list
+    Text,
+    /// Graphviz
+    Dot,
+    Mermaid,
+}
+
 /// walk the hierarchy
 /// - visit & display a list of segments/sums.
 #[derive(Parser, Debug)]
@@ -284,6 +295,14 @@ struct Cli {
     #[arg(short='s')]
     short: bool,
 
+    /// how to display the hierarchy
+    #[arg(long, value_enum, default_value_t = Format::Text, conflicts_with_all = ["replace", "clone"])]
+    format: Format,
+
+    /// describe the nodes as JSON, see the readme
+    #[arg(long, conflicts_with_all = ["format", "replace", "clone"])]
+    json: bool,
+
     #[arg(long, short = 'r', num_args(2))]
     replace: Vec<String>,
 
@@ -340,6 +359,28 @@ fn main() {
 
     info!("Start from the HEAD = {}", &root);
 
+    if cli.json {
+        let mut nodes = Vec::new();
+        walk_down(&repository, &root,
+                  |repository, node, object_map| {
+                      nodes.push(describe::describe_node(repository, node, object_map)
+                                 .expect("should describe the node"));
+                  });
+        println!("{}", Listing::new(nodes).to_json());
+        return;
+    }
+
+    if cli.format != Format::Text {
+        let hierarchy_graph = find_hierarchy(&repository, root);
+        let graph = ExportGraph::new(&repository, &hierarchy_graph);
+        match cli.format {
+            Format::Dot => print!("{}", graph.to_dot()),
+            Format::Mermaid => print!("{}", graph.to_mermaid()),
+            Format::Text => unreachable!(),
+        }
+        return;
+    }
+
     // clone.
     if !cli.clone.is_empty() {
 
@@ -0,0 +1,102 @@
+//
+// the inverse of git-walk-down: who builds on a given branch.
+
+use clap::Parser;
+use git2::Repository;
+
+use colored::Colorize;
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use git_hierarchy::utils::{extract_name, init_tracing};
+use git_hierarchy::base::open_repository;
+use git_hierarchy::dependents::{Dependent, ReverseIndex};
+use git_hierarchy::describe::describe_by_name;
+
+use ::git_hierarchy::git_hierarchy::{segment_fmt, sum_fmt, plain_ref_fmt};
+
+#[allow(unused)]
+use tracing::{debug, info};
+
+
+/// list the segments & sums (transitively) built on top of a branch
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    #[arg(long, short='g')]
+    directory: Option<PathBuf>,
+
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// segment, sum or plain branch. Default: the current branch
+    reference: Option<String>,
+}
+
+/// Whether the node @name needs a rebase/re-merge, itself or because one of its
+/// bases/summands among the @listed (full ref names) does.  None if it cannot be loaded.
+fn node_stale(repository: &Repository, name: &str, listed: &HashSet<String>,
+              known: &mut HashMap<String, Option<bool>>) -> Option<bool> {
+    if let Some(stale) = known.get(name) {
+        return *stale;
+    }
+    // against cycles.
+    known.insert(name.to_owned(), Some(false));
+
+    let stale = describe_by_name(repository, name).ok().map(|info| {
+        !info.uptodate || info.base.iter().chain(info.summands.iter().flatten())
+            .filter(|below| listed.contains(*below))
+            .any(|below| node_stale(repository, extract_name(below), listed, known) == Some(true))
+    });
+    known.insert(name.to_owned(), stale);
+    stale
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    init_tracing(cli.verbose);
+
+    let repository = open_repository(cli.directory.as_ref()).unwrap();
+
+    let root = match cli.reference {
+        Some(name) => repository.resolve_reference_from_short_name(&name),
+        None => repository.head(),
+    }.expect("reference should exist");
+    let root_name = root.name().unwrap().to_owned();
+    info!("Start from {}", root_name);
+
+    let index = ReverseIndex::new(&repository).expect("should scan the hierarchy refs");
+
+    let tree = index.tree(&root_name);
+    let listed: HashSet<String> = tree.iter().map(|entry| entry.dependent.ref_name()).collect();
+    let mut known = HashMap::new();
+
+    let mut total = 0;
+    let mut stale = 0;
+    println!("{}", plain_ref_fmt(&root_name));
+    for entry in tree {
+        let indent = "  ".repeat(entry.depth);
+        let name = match &entry.dependent {
+            Dependent::Segment(name) => format!("segment {}", segment_fmt(name)),
+            Dependent::Sum(name) => format!("sum {}", sum_fmt(name)),
+        };
+        if entry.repeated {
+            println!("{}{} (see above)", indent, name);
+            continue;
+        }
+
+        total += 1;
+        let state = match node_stale(&repository, entry.dependent.name(), &listed, &mut known) {
+            Some(false) => "up-to-date".normal(),
+            Some(true) => {
+                stale += 1;
+                "need-rebase".bright_red().on_white()
+            }
+            None => "broken".red(),
+        };
+        println!("{}{}\t{}", indent, name, state);
+    }
+    println!("{} dependents, {} need rebasing", total, stale);
+}
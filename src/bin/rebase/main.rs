@@ -1,5 +1,4 @@
 #![deny(elided_lifetimes_in_paths)]
-#![feature(iter_next_chunk)]
 // walk the hierarchy
 // - assemble list of segments/sums.
 // - graph, toposort
@@ -18,23 +17,34 @@ use git2::{Branch, BranchType, Error, Commit, Reference, ReferenceFormat, Reposi
 #[allow(unused_imports)]
 use tracing::{span, Level, debug, info, warn,error};
 
-use ::git_hierarchy::base::{checkout_new_head_at, git_same_ref, open_repository};
-use ::git_hierarchy::execute::git_run;
+use ::git_hierarchy::base::open_repository;
 use ::git_hierarchy::utils::{
     extract_name, iterator_symmetric_difference, init_tracing,
 };
-use ::git_hierarchy::rebase::{check_segment, check_sum,
-                              rebase_segment,rebase_segment_continue,
-                              segment_to_continue,
+use ::git_hierarchy::rebase::{check_segment, check_sum, sum_difference,
+                              commit_sum,
+                              rebase_segment,rebase_segment_continue,rebase_segment_skip,
+                              rebase_abort, exec_node, rebase_segment_in_memory,
                               RebaseResult, RebaseError};
-use std::collections::HashMap;
+use ::git_hierarchy::fetch::{DivergedPolicy, UpdateOutcome,
+                             branch_upstream, fetch, tracking_source, update_from_upstream};
+use ::git_hierarchy::push::{hierarchy_leases, push_with_lease};
+use ::git_hierarchy::autostash::{autostash_config, stash, unstash};
+use ::git_hierarchy::rebase_state::{DroppedCommit, EmptyPolicy, RebaseOptions, RebaseState};
+use ::git_hierarchy::rebase_worktree::{create_worktree, is_worktree_at, remove_worktree};
+use ::git_hierarchy::snapshot::{OriginalHead, RefSnapshot};
+use std::collections::{HashMap, HashSet};
 use std::iter::Iterator;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, mpsc};
+use std::thread;
 
-use crate::graph::discover_pet::find_hierarchy;
+use crate::graph::discover_pet::{HierarchyGraph, find_hierarchy};
 
 // I need both:
 #[allow(unused)]
-use ::git_hierarchy::git_hierarchy::{GitHierarchy, Segment, Sum, load};
+use ::git_hierarchy::git_hierarchy::{GitHierarchy, Segment, Sum, load,
+                                     segment_fmt, sum_fmt, plain_ref_fmt};
 
 use std::path::PathBuf;
 use std::process::exit;
@@ -48,33 +58,9 @@ use colored::Colorize;
 
 use ::git_hierarchy::graph;
 use graph::discover::NodeExpander;
+use graph::schedule::Schedule;
 
 
-/// Compose commit message for the Sum/Merge of .... components given by the
-/// first/others.
-fn get_merge_commit_message<'a, 'b, 'c, Iter>(
-    sum_name: &'b str,
-    first: &'c str,
-    others: Iter,
-) -> String
-where
-    Iter: Iterator<Item = &'a str>,
-{
-    let mut message = format!("Sum: {sum_name}\n\n{}", first);
-
-    const NAMES_PER_LINE: usize = 3;
-    for (i, name) in others.enumerate() {
-        message.push_str(" + ");
-        message.push_str(name);
-
-        if i % NAMES_PER_LINE == 0 {
-            // exactly same as push_str()
-            message += "\n"
-        }
-    }
-    message
-}
-
 /// Given @sum, check if it's up-to-date.
 ///
 /// If not: create a new git merge commit.
@@ -129,114 +115,15 @@ fn remerge_sum<'repo>(
     } else {
         info!("so the sum is not up-to-date!");
 
-        let first = graphed_summands.first().unwrap();
+        // Reference -> Commit, in the order of the summands: so are the parents.
+        let commits : Vec<Commit<'_>> = graphed_summands.iter()
+            .map(|gh| gh.commit())
+            .collect::<Result<_, _>>()?;
+        let names : Vec<&str> = graphed_summands.iter()
+            .map(|gh| gh.node_identity())
+            .collect();
 
-        let message = get_merge_commit_message(
-            sum.name(),
-            first.node_identity(), // : &GitHierarchy
-            graphed_summands.iter()
-                .skip(1).map(|x| x.node_identity()),
-        );
-
-        if graphed_summands.len() > 2 {
-            checkout_new_head_at(repository, None, &first.commit()?);
-
-            // use  git_run or?
-            let mut cmdline = vec![
-                "merge",
-                "-m",
-                &message, // why is this not automatic?
-                "--rerere-autoupdate",
-                "--strategy",
-                "octopus",
-                "--strategy",
-                "recursive",
-                "--strategy-option",
-                "patience",
-                "--strategy-option",
-                "ignore-space-change",
-            ];
-            cmdline.extend(graphed_summands.iter().map(|s| s.node_identity()));
-
-            git_run(repository, &cmdline)?;
-            // "commit": move the SUM head with reflog message:
-            sum.reset(repository.head()?.resolve()?.target().unwrap());
-        } else {
-            // libgit2
-            assert!(checkout_new_head_at(repository, None, &first.commit()?).is_none());
-
-            // Options:
-            let mut merge_opts = MergeOptions::new();
-            merge_opts.fail_on_conflict(true)
-                .standard_style(true)
-                .ignore_whitespace(true)
-                .patience(true)
-                .minimal(true)
-                ;
-
-            let mut checkout_opts = CheckoutBuilder::new();
-            checkout_opts.safe();
-
-
-            // one more conversion:
-            // we have Vec<GitHierarchy> ->  Vec<Commit> need..... Vec<AnnotatedCommit>
-            //
-            let annotated_commits : Vec<AnnotatedCommit<'_>> =
-                graphed_summands.iter().skip(1).map(
-                    |gh| {
-                        // fixme:
-                        let oid = gh.commit().unwrap().id();
-                        repository.find_annotated_commit(oid).unwrap()
-                    }).collect();
-            // the references vec:
-            let annotated_commits_refs = annotated_commits.iter().collect::<Vec<_>>();
-
-            debug!("Calling merge()");
-            repository.merge(
-                &annotated_commits_refs,
-                Some(&mut merge_opts),
-                Some(&mut checkout_opts)
-            ).expect("Merge should succeed");
-
-            // make if a function:
-            // oid = save_index_with( message, signature);
-            let mut index = repository.index().unwrap();
-            if index.has_conflicts() {
-                info!("{}: SORRY conflicts detected", line!());
-                exit(1);
-            }
-            let id = index.write_tree().unwrap();
-            let tree = repository.find_tree(id).unwrap();
-
-            let sig = repository.signature().unwrap();
-
-
-            // Create the commit:
-            // another one: Reference -> Commit -> Oid >>> lookup >>> AnnotatedCommit->Oid
-            let commits : Vec<Commit<'_>> = graphed_summands.iter()
-                .map(|gh| gh.commit().unwrap()) // fixme!
-                .collect();
-            // references
-            let commits_refs = commits.iter().collect::<Vec<_>>();
-
-            debug!("Calling merge()");
-
-            let new_oid = repository.commit(
-                Some("HEAD"),
-                &sig, // author(),
-                &sig, // committer(),
-                &message,
-                &tree,
-                // this is however already stored in the directory:
-                &commits_refs,
-                // Error: "failed to create commit: current tip is not the first parent"
-            ).unwrap();
-
-            repository.cleanup_state().expect("cleaning up should succeed");
-
-            // this both on the Repo/storer both here in our Data ?
-            sum.reset(new_oid);
-        }
+        commit_sum(repository, sum, &commits, &names)?;
     }
 
     // do we have a hint -- another merge?
@@ -244,75 +131,36 @@ fn remerge_sum<'repo>(
     Ok(RebaseResult::Done)
 }
 
-/// Given full git-reference name /refs/remotes/xx/bb return xx and bb
-fn extract_remote_name(name: &str) -> (&str, &str) {
-    debug!("extract_remote_name: {:?}", name);
-    // let norm = Reference::normalize_name(reference.name().unwrap(), ReferenceFormat::NORMAL).unwrap();
-
-    let split_char = '/';
-
-    let (prefix, rest) = name.split_once(split_char).unwrap();
-    assert_eq!(prefix, "refs");
-    let (prefix, rest) = rest.split_once(split_char).unwrap();
-    assert_eq!(prefix, "remotes");
-
-    let (remote, branch) = rest.split_once(split_char).unwrap();
-    (remote, branch)
-}
-
-fn fetch_upstream_of(repository: &Repository, reference: &Reference<'_>) -> Result<(), Error> {
+fn fetch_upstream_of(repository: &Repository,
+                     reference: &Reference<'_>,
+                     options: &RebaseOptions,
+) -> Result<(), RebaseError> {
+    let name = reference.name().unwrap();
     // resolve what to fetch.
     if reference.is_remote() {
-        let (remote_name, branch) = extract_remote_name(reference.name().unwrap());
-        let mut remote = repository.find_remote(remote_name).unwrap();
-        debug!("fetching from remote {:?}: {:?}",
-               remote_name, // remote.name().unwrap(),
-               branch);
-
-        // FetchOptions, message
-        if remote.fetch(&[branch], None, Some("part of poset-rebasing")).is_err() {
-            panic!("** Fetch failed");
-        }
+        fetch(repository, &tracking_source(repository, name)?)?;
     } else if reference.is_branch() { // and we know it's not Segment/Sum, right?
         // the user has a reason to use local branch.
         // So we don't want to change it (by fetching) without explicit permission.
         // implicit permission -- that it's just following a remote branch.
-        let name = Reference::normalize_name(reference.name().unwrap(), ReferenceFormat::NORMAL).unwrap();
-
-        // let b = Branch::wrap(*reference); // cannot move out of `*reference` which is behind a mutable reference
         info!("fetch local {name}");
-        // why redo this? see above ^^
-        let mut branch = repository
-            .find_branch(extract_name(&name), BranchType::Local)
-            .unwrap();
-
-        let upstream = branch.upstream().unwrap();
-        let upstream_name = upstream.name().unwrap().unwrap();
-
-        if git_same_ref(repository, reference, upstream.get()) {
-            // we might be behind?
-            debug!("in sync, so let's fetch & update");
-        } else {
-            // Check if still in sync, to not lose local changes.
-            panic!("{} not in sync with upstream {}; should not update.", name, upstream_name);
-            // or merge/rebase.
-        }
-        let [rem, br] = upstream_name.split('/').take(2).next_chunk().unwrap();
-
-        let mut remote = repository.find_remote(rem)?;
-
-        info!("fetch {} {} ....", rem, br);
-        if remote.fetch(&[br], None, None).is_ok() {
-            let oid = branch
-                .upstream()
-                .unwrap()
-                .get()
-                .target()
-                .expect("upstream disappeared");
-            branch
-                .get_mut()
-                .set_target(oid, "fetch & fast-forward")
-                .expect("fetch/sync failed");
+        let upstream = branch_upstream(repository, name)?;
+        fetch(repository, &upstream)?;
+
+        // local fixes are not lost: see the policy.
+        let policy = match options.diverged {
+            Some(policy) => policy,
+            None => DivergedPolicy::from_config(repository, name)?.unwrap_or_default(),
+        };
+        let upstream_name = extract_name(&upstream.tracking);
+        match update_from_upstream(repository, reference, &repository.find_reference(&upstream.tracking)?, policy)? {
+            UpdateOutcome::Left { ahead, behind } => {
+                eprintln!("{} {} has diverged from {}: {} local and {} upstream commits, left as it is",
+                          Colorize::bright_magenta("warning:"), extract_name(name), upstream_name, ahead, behind);
+            }
+            outcome => {
+                info!("{}: {:?}", name, outcome);
+            }
         }
     }
     Ok(())
@@ -321,23 +169,23 @@ fn fetch_upstream_of(repository: &Repository, reference: &Reference<'_>) -> Resu
 fn rebase_node<'repo>(
     repo: &'repo Repository,
     node: &GitHierarchy<'repo>,
-    fetch: bool,
     object_map: &HashMap<String, GitHierarchy<'repo>>,
+    state: &mut RebaseState,
 ) -> Result<RebaseResult, RebaseError> {
     match node {
         GitHierarchy::Name(_n) => {
             panic!();
         }
         GitHierarchy::Reference(r) => {
-            if fetch {
-                fetch_upstream_of(repo, r)?; // .expect("fetch failed")
+            if state.options.fetch {
+                fetch_upstream_of(repo, r, &state.options)?; // .expect("fetch failed")
             }
             Ok(RebaseResult::Done)
         }
         GitHierarchy::Segment(segment) => {
             let my_span = span!(Level::INFO, "segment", name = segment.name());
             let _enter = my_span.enter();
-            rebase_segment(repo, segment)
+            rebase_segment(repo, segment, state)
         }
         GitHierarchy::Sum(sum) => {
             remerge_sum(repo, sum, object_map)
@@ -369,15 +217,12 @@ fn check_node<'repo>(
 }
 
 
-// whole hierarchy
-fn rebase_tree(repository: &Repository,
-               root: String,
-               fetch: bool,
-               ignore: &[String],
-               skip: &[String]
-) -> Result<(), RebaseError> {
-    let hierarchy_graph = find_hierarchy(repository, root);
-
+/// Verify we can rebase the hierarchy, and create the persistent state for it.
+fn start_rebase<'repo>(repository: &'repo Repository,
+                       hierarchy_graph: &HierarchyGraph<'repo>,
+                       root: String,
+                       options: RebaseOptions,
+) -> Result<RebaseState, RebaseError> {
     // verify we can do it:
     debug!("Verify");
     for v in &hierarchy_graph.discovery_order {
@@ -390,7 +235,7 @@ fn rebase_tree(repository: &Repository,
                 .node_weight(*hierarchy_graph.labeled_nodes.get(v).unwrap())
                 .unwrap()
         );
-        if ignore.iter().any(|x| x == name) {
+        if options.ignore.iter().any(|x| x == name) {
             info!("not checking: {name}");
             continue;
         }
@@ -399,10 +244,135 @@ fn rebase_tree(repository: &Repository,
             // with context .expect("nodes should be in correct state");
     }
 
+    let autostash = if options.autostash {
+        stash(repository, options.autostash_untracked)?
+    } else {
+        None
+    };
+
+    let mut state = RebaseState::new(root,
+                                     hierarchy_graph.discovery_order.clone(),
+                                     options,
+                                     RefSnapshot::record(repository, hierarchy_graph.labeled_objects.values())?);
+    state.autostash = autostash;
+    if state.options.push {
+        // in the order, to push the bases first.
+        let nodes = hierarchy_graph.discovery_order.iter()
+            .map(|v| hierarchy_graph.labeled_objects.get(v).unwrap());
+        state.leases = hierarchy_leases(repository, nodes)?;
+    }
+    state.save(repository)?;
+    Ok(state)
+}
+
+/// --dry-run: print what `rebase_tree' would do to each node, and all the failed checks.
+fn plan_tree<'repo>(repository: &'repo Repository,
+                    hierarchy_graph: &HierarchyGraph<'repo>,
+                    options: &RebaseOptions,
+) -> Result<(), RebaseError> {
+    // full ref names of the nodes which will move.
+    let mut moving = HashSet::new();
+    let mut failures = Vec::new();
+
     for v in &hierarchy_graph.discovery_order {
-        let name = hierarchy_graph.labeled_objects.get(v).unwrap().node_identity();
+        let vertex = hierarchy_graph.labeled_objects.get(v).unwrap();
+        let name = vertex.node_identity();
+
+        if !options.ignore.iter().any(|x| x == name)
+            && let Err(e) = check_node(repository, vertex, &hierarchy_graph.labeled_objects) {
+                failures.push(format!("{}: {}", name, e));
+            }
+
+        if options.skip.iter().any(|x| x == name) {
+            println!("skip {}", name);
+            continue;
+        }
+
+        match vertex {
+            GitHierarchy::Name(_n) => {
+                panic!();
+            }
+            GitHierarchy::Reference(r) => {
+                println!("{} {}",
+                         if options.fetch { "fetch" } else { "keep" },
+                         plain_ref_fmt(r.name().unwrap()));
+            }
+            GitHierarchy::Segment(segment) => {
+                let base = segment.base(repository);
+                let base_name = base.name().unwrap();
+
+                if segment.uptodate(repository) && !moving.contains(base_name) {
+                    println!("segment {}: up-to-date on {}", segment_fmt(name), base_name);
+                } else {
+                    let new_base =
+                        if moving.contains(base_name) {
+                            "(once rebased)".to_owned()
+                        } else {
+                            base.peel_to_commit()?.id().to_string()
+                        };
+                    println!("segment {}: cherry-pick {} commits from {} onto {} {}",
+                             segment_fmt(name),
+                             segment.iter(repository)?.count(),
+                             segment.start(),
+                             base_name,
+                             new_base);
+                    moving.insert(segment.reference.borrow().name().unwrap().to_owned());
+                }
+            }
+            GitHierarchy::Sum(sum) => {
+                let (new_summands, old_parents) = sum_difference(repository, sum, &hierarchy_graph.labeled_objects);
+                let moving_summands: Vec<String> = sum.summands(repository).iter()
+                    .map(|s| s.name().unwrap().to_owned())
+                    .filter(|s| moving.contains(s))
+                    .collect();
+
+                if new_summands.is_empty() && moving_summands.is_empty() {
+                    println!("sum {}: up-to-date", sum_fmt(name));
+                } else {
+                    println!("sum {}: re-merge", sum_fmt(name));
+                    for oid in new_summands {
+                        println!("  summand at {}", oid);
+                    }
+                    for oid in old_parents {
+                        println!("  replaces parent {}", oid);
+                    }
+                    for summand in moving_summands {
+                        println!("  summand {} is rebased", summand);
+                    }
+                    moving.insert(sum.reference.borrow().name().unwrap().to_owned());
+                }
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        eprintln!("{}", "checks failed:".bright_red());
+        for failure in &failures {
+            eprintln!("  {}", failure);
+        }
+        Err(RebaseError::WrongHierarchy(failures.join(", ")))
+    }
+}
+
+// whole hierarchy, in the order stored in the @state, skipping those already done.
+fn rebase_tree<'repo>(repository: &'repo Repository,
+                      hierarchy_graph: &HierarchyGraph<'repo>,
+                      state: &mut RebaseState,
+) -> Result<(), RebaseError> {
+    let order = state.discovery_order.clone();
+    for v in &order {
+        if state.is_done(v) {
+            debug!("already done: {v}");
+            continue;
+        }
+
+        let vertex = hierarchy_graph.labeled_objects.get(v)
+            .ok_or_else(|| RebaseError::StateMismatch(format!("{} is not part of the hierarchy anymore", v)))?;
+        let name = vertex.node_identity();
 
-        if skip.iter().any(|x| x == name) {
+        if state.options.skip.iter().any(|x| x == name) {
             info!("Skipping: {name}");
             continue;
         }
@@ -410,19 +380,215 @@ fn rebase_tree(repository: &Repository,
         debug!(
             "{:?} {:?} {:?}",
             v,
-            hierarchy_graph.labeled_objects.get(v).unwrap().node_identity(),
+            name,
             hierarchy_graph.graph
                 .node_weight(*hierarchy_graph.labeled_nodes.get(v).unwrap())
                 .unwrap()
         );
-        let vertex = hierarchy_graph.labeled_objects.get(v).unwrap();
 
-        rebase_node(repository, vertex, fetch, &hierarchy_graph.labeled_objects)?;
+        rebase_node(repository, vertex, &hierarchy_graph.labeled_objects, state)?;
+        // afresh, fetching does not update the `vertex'.
+        let rebased = load(repository, v)?;
+        let head = rebased.commit()?.id();
+        if let Some(command) = state.options.exec.clone() {
+            if state.original.unchanged(&rebased)? {
+                debug!("{} did not change, not running the exec", v);
+            } else {
+                // failing, --continue re-runs it, on the already rebased node.
+                state.end_node(repository)?;
+                exec_node(repository, vertex, &command)?;
+            }
+        }
+        state.node_done(repository, v, head)?;
+    }
+    report_dropped(state);
+    if let Some(path) = &state.worktree {
+        remove_worktree(path)?;
+        warn_checkout_moved(repository, &state.original);
+    } else {
+        // if this fails, --continue retries it.
+        state.original.return_to_head(repository)?;
+    }
+    RebaseState::remove(repository)?;
+    if let Some(stash) = state.autostash {
+        unstash(repository, stash)?;
+    }
+    if state.options.push {
+        push_rebased(repository, state)?;
     }
     debug!("done");
     Ok(())
 }
 
+// --push: the nodes which moved, with the leases from before the rebase.
+fn push_rebased(repository: &Repository, state: &RebaseState) -> Result<(), RebaseError> {
+    let mut refused = Vec::new();
+    for lease in &state.leases {
+        if repository.refname_to_id(&lease.branch).ok() == state.original.recorded(&lease.branch) {
+            debug!("not rewritten: {}", lease.branch);
+            continue;
+        }
+        let status = push_with_lease(repository, lease)?;
+        eprintln!("{}: {}", extract_name(&lease.branch), status);
+        if status.failed() {
+            refused.push(extract_name(&lease.branch).to_owned());
+        }
+    }
+    if refused.is_empty() {
+        Ok(())
+    } else {
+        Err(RebaseError::PushRefused(refused))
+    }
+}
+
+/// --jobs: one node, with a @repository of its own.  Segments & sums in a worker
+/// thread, plain branches -- fetching -- in the main one.
+/// Returns where it moved to, and the commits dropped, or None if it needs the worktree.
+fn rebase_node_in_memory(repository: &Repository,
+                         name: &str,
+                         options: &RebaseOptions,
+) -> Result<Option<(Oid, Vec<DroppedCommit>)>, RebaseError> {
+    let node = load(repository, name)?;
+    let mut dropped = Vec::new();
+    match &node {
+        GitHierarchy::Name(_n) => {
+            panic!();
+        }
+        GitHierarchy::Reference(r) => {
+            if options.fetch {
+                fetch_upstream_of(repository, r, options)?;
+            }
+        }
+        GitHierarchy::Segment(segment) => {
+            if rebase_segment_in_memory(repository, segment, options.empty, &mut dropped)?.is_none() {
+                return Ok(None);
+            }
+        }
+        GitHierarchy::Sum(sum) => {
+            // other threads moved the summands, load them afresh.
+            let object_map = sum.summands(repository).iter()
+                .map(|s| {
+                    let summand = s.name().unwrap();
+                    Ok((summand.to_owned(), load(repository, summand)?))
+                })
+                .collect::<Result<HashMap<_, _>, Error>>()?;
+            remerge_sum(repository, sum, &object_map)?;
+        }
+    }
+    Ok(Some((load(repository, name)?.commit()?.id(), dropped)))
+}
+
+// --jobs: record the @result of the node @i, or leave it to `rebase_tree'.
+fn finish_node_in_memory(repository: &Repository,
+                         state: &mut RebaseState,
+                         schedule: &mut Schedule,
+                         name: &str,
+                         i: usize,
+                         result: Result<Option<(Oid, Vec<DroppedCommit>)>, RebaseError>,
+) -> Result<(), RebaseError> {
+    match result {
+        Ok(Some((oid, dropped))) => {
+            state.add_dropped(repository, dropped)?;
+            state.node_done(repository, name, oid)?;
+            schedule.done(i);
+        }
+        Ok(None) => {
+            info!("{} needs the worktree", name);
+            schedule.failed(i);
+        }
+        Err(e) => {
+            info!("{} failed, retrying later: {}", name, e);
+            schedule.failed(i);
+        }
+    }
+    Ok(())
+}
+
+/// --jobs: rebase the nodes as soon as what they depend on is done, up to @jobs at a time.
+/// Only what can be done in memory: the nodes which stop (conflicts, empty commits,
+/// errors) and their dependents are left for `rebase_tree'.
+fn rebase_parallel<'repo>(repository: &'repo Repository,
+                          hierarchy_graph: &HierarchyGraph<'repo>,
+                          state: &mut RebaseState,
+) -> Result<(), RebaseError> {
+    let order = &hierarchy_graph.discovery_order;
+    let mut schedule = Schedule::from_graph(hierarchy_graph);
+    let options = &state.options.clone();
+
+    // Repository is Send, but not Sync.
+    let repositories = (0..state.options.jobs)
+        .map(|_| Repository::open(repository.path()))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let (job_sender, job_receiver) = mpsc::channel::<usize>();
+    let job_receiver = Mutex::new(job_receiver);
+    let (result_sender, result_receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for worker in repositories {
+            let result_sender = result_sender.clone();
+            let job_receiver = &job_receiver;
+            scope.spawn(move || {
+                loop {
+                    // not in the `while let', the lock would be held while working.
+                    let job = job_receiver.lock().unwrap().recv();
+                    // ends when the `job_sender' is dropped.
+                    let Ok(i) = job else { break };
+
+                    // a panic would leave the main thread waiting.
+                    let result = panic::catch_unwind(AssertUnwindSafe(
+                        || rebase_node_in_memory(&worker, &order[i], options)));
+                    if result_sender.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // moved in, so that returning early stops the workers.
+        let job_sender = job_sender;
+
+        loop {
+            while let Some(i) = schedule.start_next() {
+                let vertex = hierarchy_graph.labeled_objects.get(&order[i]).unwrap();
+                let name = vertex.node_identity();
+                if state.is_done(&order[i]) || state.options.skip.iter().any(|x| x == name) {
+                    schedule.done(i);
+                    continue;
+                }
+                if let GitHierarchy::Reference(_) = vertex {
+                    // the fetches, one at a time.
+                    let result = rebase_node_in_memory(repository, &order[i], options);
+                    finish_node_in_memory(repository, state, &mut schedule, &order[i], i, result)?;
+                    continue;
+                }
+                debug!("starting {}", name);
+                job_sender.send(i).unwrap();
+            }
+            if schedule.running() == 0 {
+                return Ok(());
+            }
+
+            let (i, result) = result_receiver.recv().unwrap();
+            let result = result.map_err(|_| RebaseError::WorkerPanicked(order[i].clone()))?;
+            finish_node_in_memory(repository, state, &mut schedule, &order[i], i, result)?;
+        }
+    })
+}
+
+// whole hierarchy: first whatever the --jobs can do, then the rest.
+fn run_tree<'repo>(repository: &'repo Repository,
+                   hierarchy_graph: &HierarchyGraph<'repo>,
+                   state: &mut RebaseState,
+) -> Result<(), RebaseError> {
+    if state.options.jobs > 1 {
+        rebase_parallel(repository, hierarchy_graph, state)?;
+        // the nodes moved under the feet of `hierarchy_graph'.
+        let hierarchy_graph = find_hierarchy(repository, state.root.clone());
+        return rebase_tree(repository, &hierarchy_graph, state);
+    }
+    rebase_tree(repository, hierarchy_graph, state)
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
@@ -437,13 +603,170 @@ struct Cli {
 
     #[arg(short, long = "continue")]
     cont: bool,
+
+    /// Restore all refs to the state before the rebase started
+    #[arg(long, conflicts_with = "cont")]
+    abort: bool,
+
+    /// Only print what would be done, and all the failed checks
+    #[arg(short = 'n', long, conflicts_with_all = ["cont", "abort"])]
+    dry_run: bool,
+
     root_reference: Option<String>,
 
     #[arg(short, long = "ignore")]
     ignore: Vec<String>,
 
+    /// Don't rebase these nodes
     #[arg(short, long = "skip")]
-    skip: Vec<String>
+    skip: Vec<String>,
+
+    /// Drop the commit we stopped at, and continue (`git rebase --skip')
+    #[arg(long, conflicts_with_all = ["cont", "abort", "dry_run"])]
+    skip_commit: bool,
+
+    /// Run this shell command on each segment & sum the rebase changed, stop when it fails
+    #[arg(short = 'x', long, value_name = "CMD", conflicts_with_all = ["cont", "abort", "skip_commit"])]
+    exec: Option<String>,
+
+    /// Stash local changes before, and re-apply them after the rebase.
+    /// Default: the `hierarchy.autostash' git config
+    #[arg(long, conflicts_with_all = ["cont", "abort", "skip_commit", "dry_run"])]
+    autostash: bool,
+
+    /// With --autostash, stash the untracked files too.
+    /// Default: the `hierarchy.autostashUntracked' git config
+    #[arg(short = 'u', long)]
+    include_untracked: bool,
+
+    /// Rebase in a private linked worktree, leaving the current checkout alone
+    #[arg(long, conflicts_with_all = ["cont", "abort", "skip_commit", "dry_run", "autostash"])]
+    worktree: bool,
+
+    /// Rebase up to N independent segments & sums at once, in memory
+    #[arg(short, long, value_name = "N", default_value_t = 1,
+          conflicts_with_all = ["cont", "abort", "skip_commit", "exec"])]
+    jobs: usize,
+
+    /// What to do with fetched plain branches which have local commits:
+    /// ff-only, rebase, merge or leave.
+    /// Default: the `branch.<name>.hierarchyDiverged' or `hierarchy.diverged' git config, or ff-only
+    #[arg(long, value_name = "POLICY", conflicts_with_all = ["cont", "abort"])]
+    diverged: Option<DivergedPolicy>,
+
+    /// Push the rebased segments & sums to their upstreams, unless they moved meanwhile
+    #[arg(long, conflicts_with_all = ["cont", "abort", "skip_commit"])]
+    push: bool,
+
+    /// What to do with commits becoming empty: drop, keep or stop.
+    /// Default: the `hierarchy.empty' git config, or stop
+    #[arg(long, value_name = "POLICY", conflicts_with_all = ["cont", "abort"])]
+    empty: Option<EmptyPolicy>,
+}
+
+fn report_dropped(state: &RebaseState) {
+    if state.dropped.is_empty() {
+        return;
+    }
+    eprintln!("dropped {} commits:", state.dropped.len());
+    for dropped in &state.dropped {
+        eprintln!("  {} {}: {}", segment_fmt(&dropped.segment), dropped.oid, dropped.summary);
+    }
+}
+
+// the error, what caused it, and what the user can do.
+fn report_error(error: &RebaseError) {
+    eprintln!("{}: {}", Colorize::red("Failed"), error);
+
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        eprintln!("  caused by: {}", cause);
+        source = cause.source();
+    }
+
+    match error {
+        RebaseError::Conflict { .. } => {
+            eprintln!("resolve them, and either commit or stage them, then: git-rebase-poset --continue\n(or --skip-commit to drop the commit)");
+        }
+        RebaseError::EmptyCommit { .. } => {
+            eprintln!("git-rebase-poset --skip-commit drops it, commit it first (--allow-empty) to keep it");
+        }
+        RebaseError::ExecFailed { .. } => {
+            eprintln!("fix it, then: git-rebase-poset --continue runs it again");
+        }
+        RebaseError::Diverged { branch, .. } => {
+            eprintln!("git config branch.{}.hierarchyDiverged rebase|merge|leave, then: git-rebase-poset --continue\n(or --abort, and start over with --diverged)", branch);
+        }
+        RebaseError::UpstreamMergeCommit { branch, .. } => {
+            eprintln!("git config branch.{}.hierarchyDiverged merge, then: git-rebase-poset --continue\n(or --abort, and start over with --diverged merge)", branch);
+        }
+        RebaseError::NoUpstream { branch } => {
+            eprintln!("git branch --set-upstream-to=<upstream> {}, then: git-rebase-poset --continue\n(or --abort, and start over with --no-fetch)", branch);
+        }
+        RebaseError::PushRefused(_) => {
+            eprintln!("the rebase is done, fetch and check the refused ones, then: git-hierarchy push");
+        }
+        RebaseError::DirtyWorktree { .. } | RebaseError::NothingToSkip { .. } => {
+            eprintln!("commit or stash your changes, then: git-rebase-poset --continue");
+        }
+        _ => {}
+    }
+}
+
+/// --worktree: create a linked worktree, and do all the work in it.
+fn rebase_in_worktree(repository: &Repository,
+                      root: String,
+                      options: RebaseOptions,
+) -> Result<(), RebaseError> {
+    let path = create_worktree(repository)?;
+    let worktree = Repository::open(&path)?;
+    let hierarchy_graph = find_hierarchy(&worktree, root.clone());
+
+    let mut state = match start_rebase(&worktree, &hierarchy_graph, root, options) {
+        Ok(state) => state,
+        Err(e) => {
+            remove_worktree(&path)?;
+            return Err(e);
+        }
+    };
+    // the user's HEAD, not the detached one of the worktree.
+    state.original.head = OriginalHead::of(repository)?;
+    state.worktree = Some(path);
+    state.save(&worktree)?;
+    run_tree(&worktree, &hierarchy_graph, &mut state)
+}
+
+// --worktree: the checked out branch moved under the user's feet.
+fn warn_checkout_moved(repository: &Repository, original: &RefSnapshot) {
+    if let OriginalHead::Branch(name) = &original.head
+        && let Some(old) = original.recorded(name)
+        && let Ok(reference) = repository.find_reference(name)
+        && reference.target() != Some(old) {
+            eprintln!("{} {} is checked out, and was rebased. To update the checkout: git read-tree -m -u {} HEAD",
+                      Colorize::bright_magenta("warning:"), extract_name(name), old);
+        }
+}
+
+// resume from the persistent state. Possibly dropping the commit we stopped at.
+fn continue_rebase(repository: &Repository, skip: bool) -> Result<(), RebaseError> {
+    let mut state = RebaseState::load(repository)?.ok_or(RebaseError::NotInProgress)?;
+    if let Some(path) = &state.worktree
+        && !is_worktree_at(repository, path) {
+            debug!("continuing in {:?}", path);
+            return continue_rebase(&Repository::open(path)?, skip);
+        }
+    state.validate(repository)?;
+
+    if skip {
+        rebase_segment_skip(repository, &mut state)?;
+    } else if state.in_progress.is_some() {
+        // old: rebase_continue_git1(repository, &segment_name)
+        rebase_segment_continue(repository, &mut state)?;
+    }
+
+    // we might have stopped outside of a segment, e.g. on a sum.
+    let hierarchy_graph = find_hierarchy(repository, state.root.clone());
+    run_tree(repository, &hierarchy_graph, &mut state)
 }
 
 fn main() {
@@ -454,37 +777,93 @@ fn main() {
     let repository = open_repository(cli.directory.as_ref()).expect("should find the Git directory");
 
 
-    if cli.cont {
-        // old: rebase_continue_git1(repository, &segment_name)
-        rebase_segment_continue(&repository).unwrap();
-    } else {
-        if let Some((segment_name, _)) = segment_to_continue(&repository) {
-            eprintln!("{} {}",Colorize::bright_magenta("rebase underway, must use continue -c"), segment_name);
-            exit(1);
+    if cli.abort {
+        if let Err(e) = rebase_abort(&repository) {
+            report_error(&e);
+            exit(-1);
         }
+        eprintln!("{}",Colorize::green("Aborted"));
+        return;
     }
 
-    let root = cli.root_reference // if in detached HEAD -- will panic.
-        .unwrap_or_else(|| repository.head().unwrap().name().unwrap().to_owned());
+    let result =
+        if cli.cont || cli.skip_commit {
+            continue_rebase(&repository, cli.skip_commit)
+        } else {
+            if let Ok(Some(state)) = RebaseState::load(&repository) {
+                eprintln!("{} {}",Colorize::bright_magenta("rebase underway, use --continue or --abort"),
+                          state.in_progress.map(|p| p.node).unwrap_or(state.root));
+                exit(1);
+            }
+
+            let root = cli.root_reference // if in detached HEAD -- will panic.
+                .unwrap_or_else(|| repository.head().unwrap().name().unwrap().to_owned());
 
-    let root = GitHierarchy::Name(root); // not load?
+            let root = GitHierarchy::Name(root); // not load?
 
-    debug!("root is {}", root.node_identity());
+            debug!("root is {}", root.node_identity());
 
-    // todo: I must rewrite ignore to full ref names!
-    if !cli.skip.is_empty() {
-        // rewrite it:
-        for e in cli.skip.iter_mut() {
-            // rewrite String:
-            e.replace_range(..e.len(), repository.resolve_reference_from_short_name(e).unwrap().name().unwrap());
-        }
-    }
+            // todo: I must rewrite ignore to full ref names!
+            if !cli.skip.is_empty() {
+                // rewrite it:
+                for e in cli.skip.iter_mut() {
+                    // rewrite String:
+                    e.replace_range(..e.len(), repository.resolve_reference_from_short_name(e).unwrap().name().unwrap());
+                }
+            }
 
-    if let Err(e) = rebase_tree(&repository, root.node_identity().to_owned(), !cli.no_fetch,
-                                &cli.ignore,
-                                &cli.skip)
-    {
-        eprintln!("Failed: {:?}", e); // RebaseError
+            let empty = match cli.empty.map(Ok).unwrap_or_else(|| EmptyPolicy::from_config(&repository)
+                                                                .map(Option::unwrap_or_default)) {
+                Ok(empty) => empty,
+                Err(e) => {
+                    report_error(&e);
+                    exit(-1);
+                }
+            };
+
+            let (autostash, autostash_untracked) = match autostash_config(&repository) {
+                Ok(config) => config,
+                Err(e) => {
+                    report_error(&e);
+                    exit(-1);
+                }
+            };
+
+            let options = RebaseOptions {
+                fetch: !cli.no_fetch,
+                ignore: cli.ignore,
+                skip: cli.skip,
+                empty,
+                exec: cli.exec,
+                autostash: cli.autostash || autostash,
+                autostash_untracked: cli.include_untracked || autostash_untracked,
+                worktree: cli.worktree,
+                jobs: cli.jobs,
+                diverged: cli.diverged,
+                push: cli.push,
+            };
+            let hierarchy_graph = find_hierarchy(&repository, root.node_identity().to_owned());
+            if cli.dry_run {
+                if let Err(e) = plan_tree(&repository, &hierarchy_graph, &options) {
+                    report_error(&e);
+                    exit(-1);
+                }
+                return;
+            }
+            if options.worktree {
+                rebase_in_worktree(&repository, root.node_identity().to_owned(), options)
+            } else {
+                start_rebase(&repository, &hierarchy_graph, root.node_identity().to_owned(), options)
+                    .and_then(|mut state| run_tree(&repository, &hierarchy_graph, &mut state))
+            }
+        };
+
+    if let Err(e) = result {
+        report_error(&e);
+        if let Ok(Some(state)) = RebaseState::load(&repository)
+            && let Some(path) = state.worktree {
+                eprintln!("the rebase runs in a separate worktree: cd {}", path.display());
+            }
         exit(-1);
     } else {
         eprintln!("{}",Colorize::green("Done"));
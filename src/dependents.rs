@@ -0,0 +1,320 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// The inverse of `find_hierarchy': who builds on a given reference.
+// Scans all the refs/base/* and refs/sums/*/* symbolic references.
+
+use git2::{Error, Reference, Repository};
+
+use std::collections::{HashMap, HashSet};
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::git_hierarchy::{GitHierarchy, SEGMENT_BASE_PATTERN, SEPARATOR, SUM_SUMMAND_PATTERN, load};
+use crate::utils::concatenate;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Dependent {
+    /// the segment is based on it.
+    Segment(String),
+    /// it is a summand of the sum.
+    Sum(String),
+}
+
+impl Dependent {
+    pub fn name(&self) -> &str {
+        match self {
+            Dependent::Segment(name) | Dependent::Sum(name) => name,
+        }
+    }
+
+    /// the full name of its branch.
+    pub fn ref_name(&self) -> String {
+        concatenate("refs/heads/", self.name())
+    }
+}
+
+/// full ref name -> the segments & sums using it directly.
+pub struct ReverseIndex {
+    map: HashMap<String, Vec<Dependent>>,
+}
+
+impl ReverseIndex {
+    pub fn new(repository: &Repository) -> Result<ReverseIndex, Error> {
+        let mut map: HashMap<String, Vec<Dependent>> = HashMap::new();
+
+        for reference in repository.references_glob(&concatenate(SEGMENT_BASE_PATTERN, "*"))? {
+            let reference = reference?;
+            let (Some(name), Some(target)) = (reference.name(), reference.symbolic_target()) else {
+                warn!("skipping a base which is not a symbolic reference");
+                continue;
+            };
+            let segment = name.strip_prefix(SEGMENT_BASE_PATTERN).unwrap();
+            map.entry(target.to_owned()).or_default().push(Dependent::Segment(segment.to_owned()));
+        }
+
+        for reference in repository.references_glob(&concatenate(SUM_SUMMAND_PATTERN, "*/*"))? {
+            let reference = reference?;
+            let (Some(name), Some(target)) = (reference.name(), reference.symbolic_target()) else {
+                warn!("skipping a summand which is not a symbolic reference");
+                continue;
+            };
+            // refs/sums/<sum>/<n>, the sum name might contain "/"
+            let (sum, _index) = name.strip_prefix(SUM_SUMMAND_PATTERN).unwrap()
+                .rsplit_once(SEPARATOR).unwrap();
+            let dependents = map.entry(target.to_owned()).or_default();
+            let dependent = Dependent::Sum(sum.to_owned());
+            if !dependents.contains(&dependent) {
+                dependents.push(dependent);
+            }
+        }
+
+        debug!("reverse index of {} targets", map.len());
+        Ok(ReverseIndex { map })
+    }
+
+    /// direct dependents of the full ref name @target.
+    pub fn dependents(&self, target: &str) -> &[Dependent] {
+        self.map.get(target).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Everything transitively building on @target, depth-first, as a tree.
+    /// A node reachable by several paths is expanded only the first time.
+    pub fn tree(&self, target: &str) -> Vec<TreeEntry> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        self.tree_below(target, 1, &mut seen, &mut entries);
+        entries
+    }
+
+    fn tree_below(&self, target: &str, depth: usize,
+                  seen: &mut HashSet<Dependent>, entries: &mut Vec<TreeEntry>) {
+        for dependent in self.dependents(target) {
+            let repeated = !seen.insert(dependent.clone());
+            entries.push(TreeEntry { depth, dependent: dependent.clone(), repeated });
+            if !repeated {
+                self.tree_below(&dependent.ref_name(), depth + 1, seen, entries);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TreeEntry {
+    /// 1 for the direct dependents.
+    pub depth: usize,
+    pub dependent: Dependent,
+    /// already listed, its dependents are not repeated.
+    pub repeated: bool,
+}
+
+// as the index says it is.
+fn load_dependent<'repo>(repository: &'repo Repository, dependent: &Dependent) -> Result<GitHierarchy<'repo>, Error> {
+    let gh = load(repository, dependent.name())?;
+    match (dependent, &gh) {
+        (Dependent::Segment(_), GitHierarchy::Segment(_)) | (Dependent::Sum(_), GitHierarchy::Sum(_)) => Ok(gh),
+        _ => Err(Error::from_str(&format!("{} is broken", dependent.name()))),
+    }
+}
+
+/// Make the @dependents of @target stop using it, before @target is deleted:
+/// segments get re-based on @new_base, sums drop it from their summands.
+/// Checks all of them first, so a refusal leaves everything untouched.
+pub fn rewire<'repo>(
+    repository: &'repo Repository,
+    target: &Reference<'repo>,
+    new_base: Option<&Reference<'repo>>,
+    dependents: &[Dependent],
+) -> Result<(), Error> {
+    let target_name = target.name().expect("should have name");
+
+    let mut loaded = Vec::with_capacity(dependents.len());
+    for dependent in dependents {
+        let gh = load_dependent(repository, dependent)?;
+        match (dependent, &gh) {
+            (Dependent::Segment(name), _) => {
+                if new_base.is_none() {
+                    return Err(Error::from_str(
+                        &format!("segment {} is based on {}, nothing to re-base it on", name, target_name)));
+                }
+            }
+            (Dependent::Sum(name), GitHierarchy::Sum(sum)) => {
+                // at least 2 must remain.
+                if sum.summands.len() <= 2 {
+                    return Err(Error::from_str(&format!(
+                        "{} would have less than 2 summands without {}, delete it instead: git-sum delete {}",
+                        name, target_name, name)));
+                }
+            }
+            _ => unreachable!(),
+        }
+        loaded.push(gh);
+    }
+
+    for gh in loaded {
+        match gh {
+            GitHierarchy::Segment(segment) => {
+                let new_base = new_base.unwrap();
+                info!("re-basing {} on {}", segment.name(), new_base.name().unwrap());
+                segment.set_base(repository, new_base);
+            }
+            GitHierarchy::Sum(mut sum) => {
+                info!("removing {} from {}", target_name, sum.name());
+                sum.remove_summands(repository, std::iter::once(target))?;
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Make the @dependents of @target use @replacement instead, before @target is deleted:
+/// segments get re-based on it, sums take it in the place of @target -- or just drop
+/// @target, if they have both and at least 2 summands remain.  Checks all of them
+/// first, like `rewire'.
+pub fn redirect<'repo>(
+    repository: &'repo Repository,
+    target: &Reference<'repo>,
+    replacement: &Reference<'repo>,
+    dependents: &[Dependent],
+) -> Result<(), Error> {
+    let replacement_name = replacement.name().expect("should have name");
+
+    let mut loaded = Vec::with_capacity(dependents.len());
+    for dependent in dependents {
+        if dependent.ref_name() == replacement_name {
+            return Err(Error::from_str(&format!("{} cannot use itself", replacement_name)));
+        }
+        let gh = load_dependent(repository, dependent)?;
+        if let GitHierarchy::Sum(sum) = &gh
+            && sum.summands.iter().any(|s| s.symbolic_target() == Some(replacement_name))
+            && sum.summands.len() <= 2
+        {
+            return Err(Error::from_str(&format!(
+                "{} would be left with {} only, delete it instead: git-sum delete {}",
+                sum.name(), replacement_name, sum.name())));
+        }
+        loaded.push(gh);
+    }
+
+    for gh in loaded {
+        match gh {
+            GitHierarchy::Segment(segment) => {
+                info!("re-basing {} on {}", segment.name(), replacement_name);
+                segment.set_base(repository, replacement);
+            }
+            GitHierarchy::Sum(mut sum) => {
+                if sum.summands.iter().any(|s| s.symbolic_target() == Some(replacement_name)) {
+                    sum.remove_summands(repository, std::iter::once(target))?;
+                } else {
+                    sum.replace_summand(target, replacement)?;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tree() {
+        let seg = |n: &str| Dependent::Segment(n.to_owned());
+        let sum = |n: &str| Dependent::Sum(n.to_owned());
+        // master <- a <- b, master <- c, top = b + c
+        let index = ReverseIndex { map: HashMap::from([
+            ("refs/heads/master".to_owned(), vec![seg("a"), seg("c")]),
+            ("refs/heads/a".to_owned(), vec![seg("b")]),
+            ("refs/heads/b".to_owned(), vec![sum("top")]),
+            ("refs/heads/c".to_owned(), vec![sum("top")]),
+        ])};
+
+        let tree = index.tree("refs/heads/master");
+        let flat: Vec<_> = tree.iter().map(|e| (e.depth, e.dependent.name(), e.repeated)).collect();
+        assert_eq!(flat, vec![(1, "a", false), (2, "b", false), (3, "top", false),
+                              (1, "c", false), (2, "top", true)]);
+        assert!(index.tree("refs/heads/top").is_empty());
+    }
+
+    #[test]
+    fn test_rewire() {
+        use crate::git_hierarchy::{Segment, Sum};
+        use crate::testing::{TempDir, empty_commit, init};
+
+        let dir = TempDir::new("rewire");
+        let repository = init(dir.path());
+        let oid = empty_commit(&repository, "first", &[]);
+
+        // master <- gone <- above, pair = gone + master, trio = gone + above + master
+        let master = repository.reference("refs/heads/master", oid, true, "test").unwrap();
+        let gone = Segment::create(&repository, "gone", &master, oid, oid).unwrap();
+        let gone = gone.reference.borrow();
+        let above = Segment::create(&repository, "above", &gone, oid, oid).unwrap();
+        let above = above.reference.borrow();
+        Sum::create(&repository, "pair", [&*gone, &master].into_iter(), None).unwrap();
+        Sum::create(&repository, "trio", [&*gone, &*above, &master].into_iter(), None).unwrap();
+
+        let index = ReverseIndex::new(&repository).unwrap();
+        let dependents = index.dependents("refs/heads/gone");
+        assert_eq!(dependents.len(), 3);
+        assert!(rewire(&repository, &gone, Some(&master), dependents).is_err());
+        assert_eq!(ReverseIndex::new(&repository).unwrap().dependents("refs/heads/gone"), dependents);
+
+        let others: Vec<_> = dependents.iter().filter(|d| d.name() != "pair").cloned().collect();
+        rewire(&repository, &gone, Some(&master), &others).unwrap();
+        let index = ReverseIndex::new(&repository).unwrap();
+        assert_eq!(index.dependents("refs/heads/gone"), &[Dependent::Sum("pair".to_owned())]);
+        assert_eq!(index.dependents("refs/heads/master").len(), 4);
+    }
+
+    #[test]
+    fn test_redirect() {
+        use crate::git_hierarchy::{Segment, Sum};
+        use crate::testing::{TempDir, empty_commit, init};
+
+        let dir = TempDir::new("redirect");
+        let repository = init(dir.path());
+        let oid = empty_commit(&repository, "first", &[]);
+
+        // master <- lower <- upper, lower <- other,
+        // pair = lower + upper, both = lower + upper + master, mixed = lower + master
+        let master = repository.reference("refs/heads/master", oid, true, "test").unwrap();
+        let lower = Segment::create(&repository, "lower", &master, oid, oid).unwrap();
+        let lower = lower.reference.borrow();
+        let upper = Segment::create(&repository, "upper", &lower, oid, oid).unwrap();
+        let upper = upper.reference.borrow();
+        Segment::create(&repository, "other", &lower, oid, oid).unwrap();
+        Sum::create(&repository, "pair", [&*lower, &*upper].into_iter(), None).unwrap();
+        Sum::create(&repository, "both", [&*lower, &*upper, &master].into_iter(), None).unwrap();
+        Sum::create(&repository, "mixed", [&*lower, &master].into_iter(), None).unwrap();
+
+        let index = ReverseIndex::new(&repository).unwrap();
+        assert!(redirect(&repository, &lower, &upper, index.dependents("refs/heads/lower")).is_err());
+
+        let summands = |name: &str| match load(&repository, name).unwrap() {
+            GitHierarchy::Sum(sum) => sum.summands.iter().map(|s| s.symbolic_target().unwrap().to_owned()).collect::<Vec<_>>(),
+            _ => panic!("not a sum"),
+        };
+        // pair would be left with upper only: nothing changes.
+        let others: Vec<_> = index.dependents("refs/heads/lower").iter()
+            .filter(|d| d.name() != "upper")
+            .cloned()
+            .collect();
+        assert!(redirect(&repository, &lower, &upper, &others).is_err());
+        assert_eq!(ReverseIndex::new(&repository).unwrap().dependents("refs/heads/lower"),
+                   index.dependents("refs/heads/lower"));
+        assert_eq!(summands("pair"), vec!["refs/heads/lower", "refs/heads/upper"]);
+
+        let others: Vec<_> = others.into_iter().filter(|d| d.name() != "pair").collect();
+        redirect(&repository, &lower, &upper, &others).unwrap();
+
+        let index = ReverseIndex::new(&repository).unwrap();
+        assert_eq!(index.dependents("refs/heads/lower"),
+                   &[Dependent::Segment("upper".to_owned()), Dependent::Sum("pair".to_owned())]);
+        assert_eq!(summands("both"), vec!["refs/heads/upper", "refs/heads/master"]);
+        assert_eq!(summands("mixed"), vec!["refs/heads/upper", "refs/heads/master"]);
+    }
+}
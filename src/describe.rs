@@ -0,0 +1,207 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// Machine-readable description of the nodes, for `--json'.
+// The schema is documented in the readme -- bump SCHEMA_VERSION on incompatible changes.
+
+use git2::{Error, Oid, Repository};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::git_hierarchy::{GitHierarchy, Sum, load};
+use crate::rebase::sum_difference;
+use crate::utils::{extract_name, serde_oid};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Segment,
+    Sum,
+    Reference,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitInfo {
+    #[serde(with = "serde_oid")]
+    pub oid: Oid,
+    pub summary: String,
+}
+
+/// All fields are always present, null when not applicable to the kind.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeInfo {
+    /// short name, without refs/heads/
+    pub name: String,
+    pub kind: NodeKind,
+    /// segments: full ref name of the base.
+    pub base: Option<String>,
+    /// segments: the start commit.
+    #[serde(default, with = "serde_oid::option")]
+    pub start: Option<Oid>,
+    #[serde(with = "serde_oid")]
+    pub head: Oid,
+    /// segments: oldest first.
+    pub commits: Option<Vec<CommitInfo>>,
+    /// sums: full ref names, in summand order.
+    pub summands: Option<Vec<String>>,
+    pub uptodate: bool,
+    /// problems found, empty if up-to-date.
+    pub checks: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Listing {
+    pub version: u32,
+    pub nodes: Vec<NodeInfo>,
+}
+
+impl Listing {
+    pub fn new(nodes: Vec<NodeInfo>) -> Listing {
+        Listing { version: SCHEMA_VERSION, nodes }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("should serialize")
+    }
+}
+
+/// the summands of @sum loaded, as `check_sum' & co. want them.
+pub fn summand_objects<'repo>(repository: &'repo Repository, sum: &Sum<'repo>)
+                              -> Result<HashMap<String, GitHierarchy<'repo>>, Error> {
+    sum.summands(repository).iter()
+        .map(|s| {
+            let name = s.name().unwrap();
+            load(repository, name).map(|gh| (name.to_owned(), gh))
+        })
+        .collect()
+}
+
+/// @object_map has to contain the summands, if @node is a sum. See `summand_objects'.
+pub fn describe_node<'repo>(
+    repository: &'repo Repository,
+    node: &GitHierarchy<'repo>,
+    object_map: &HashMap<String, GitHierarchy<'repo>>,
+) -> Result<NodeInfo, Error> {
+    let head = node.commit()?.id();
+    let mut checks = Vec::new();
+
+    let info = match node {
+        GitHierarchy::Name(_) => {
+            panic!("unresolved node");
+        }
+        GitHierarchy::Segment(segment) => {
+            let base = segment.base(repository);
+            if !segment.uptodate(repository) {
+                checks.push(format!("base {} moved away from the start {}",
+                                    base.name().unwrap(), segment.start()));
+            }
+
+            let mut commits = Vec::new();
+            for oid in segment.iter(repository)? {
+                let commit = repository.find_commit(oid?)?;
+                commits.push(CommitInfo {
+                    oid: commit.id(),
+                    summary: commit.summary().unwrap_or_default().to_owned(),
+                });
+            }
+
+            NodeInfo {
+                name: segment.name().to_owned(),
+                kind: NodeKind::Segment,
+                base: Some(base.name().unwrap().to_owned()),
+                start: Some(segment.start()),
+                head,
+                commits: Some(commits),
+                summands: None,
+                uptodate: false,
+                checks: Vec::new(),
+            }
+        }
+        GitHierarchy::Sum(sum) => {
+            let count = sum.summand_count();
+            if count <= 1 {
+                checks.push(format!("not a merge, {} parent commits", count));
+            } else {
+                let (not_merged, stale) = sum_difference(repository, sum, object_map);
+                for oid in not_merged {
+                    checks.push(format!("summand commit {} is not a parent", oid));
+                }
+                for oid in stale {
+                    checks.push(format!("parent {} is not a summand", oid));
+                }
+            }
+
+            NodeInfo {
+                name: sum.name().to_owned(),
+                kind: NodeKind::Sum,
+                base: None,
+                start: None,
+                head,
+                commits: None,
+                summands: Some(sum.summands(repository).iter()
+                               .map(|s| s.name().unwrap().to_owned())
+                               .collect()),
+                uptodate: false,
+                checks: Vec::new(),
+            }
+        }
+        GitHierarchy::Reference(reference) => NodeInfo {
+            name: extract_name(reference.name().unwrap()).to_owned(),
+            kind: NodeKind::Reference,
+            base: None,
+            start: None,
+            head,
+            commits: None,
+            summands: None,
+            uptodate: false,
+            checks: Vec::new(),
+        },
+    };
+
+    Ok(NodeInfo { uptodate: checks.is_empty(), checks, ..info })
+}
+
+/// Load & describe a single node by name.
+pub fn describe_by_name(repository: &Repository, name: &str) -> Result<NodeInfo, Error> {
+    let node = load(repository, name)?;
+    let object_map = match &node {
+        GitHierarchy::Sum(sum) => summand_objects(repository, sum)?,
+        _ => HashMap::new(),
+    };
+    describe_node(repository, &node, &object_map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schema() {
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let listing = Listing::new(vec![NodeInfo {
+            name: "top".to_owned(),
+            kind: NodeKind::Sum,
+            base: None,
+            start: None,
+            head: oid,
+            commits: None,
+            summands: Some(vec!["refs/heads/a".to_owned(), "refs/heads/b".to_owned()]),
+            uptodate: true,
+            checks: Vec::new(),
+        }]);
+
+        let value: serde_json::Value = serde_json::from_str(&listing.to_json()).unwrap();
+        assert_eq!(value["version"], SCHEMA_VERSION);
+        let node = &value["nodes"][0];
+        assert_eq!(node["kind"], "sum");
+        assert_eq!(node["head"], oid.to_string());
+        // not skipped, but null
+        assert!(node["base"].is_null() && node.get("base").is_some());
+        assert_eq!(node["summands"][1], "refs/heads/b");
+    }
+}
@@ -5,9 +5,23 @@ pub use std::process::{Command, ExitStatus};
 use tracing::{debug, info, warn, error};
 
 
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("no working directory")]
     NoWorkDir,
-    ProcessError(std::io::Error),
+    #[error("cannot run the process")]
+    ProcessError(#[source] std::io::Error),
+}
+
+/// Run the @command_line with `sh -c', in the worktree of the @repository, with additional @env.
+pub fn shell_run(repository: &Repository, command_line: &str, env: &[(&str, &str)]) -> Result<ExitStatus, Error> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line)
+        .envs(env.iter().copied())
+        .current_dir(repository.workdir().ok_or(Error::NoWorkDir)?);
+
+    debug!("sh -c {}", command_line);
+    command.status().map_err(Error::ProcessError)
 }
 
 /// Invoke git with the given CLI arguments. In the directory of the @repository.
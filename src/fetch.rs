@@ -0,0 +1,375 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// Plain local branches at the bottom of a hierarchy follow their upstream.
+// After fetching, the local branch might have commits of its own: the
+// `DivergedPolicy' decides what to do with them.
+//
+// The upstream is found the way git does: `branch.<name>.remote' and
+// `branch.<name>.merge', mapped through the remote's fetch refspecs.
+
+use git2::{Buf, Commit, Direction, ErrorCode, Oid, Reference, Repository};
+use serde::{Deserialize, Serialize};
+
+use std::str::FromStr;
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::base::{conflicted_paths, detach_head_from};
+use crate::rebase::RebaseError;
+use crate::utils::extract_name;
+
+const DIVERGED_CONFIG: &str = "hierarchy.diverged";
+// branch.<name>.hierarchyDiverged
+const BRANCH_DIVERGED_CONFIG: &str = "hierarchyDiverged";
+
+const FETCH_REFLOG: &str = "poset-rebase: fetch";
+// the remote of a branch with a local upstream.
+pub(crate) const LOCAL_REMOTE: &str = ".";
+
+/// Where a branch is fetched from.
+#[derive(Debug, PartialEq)]
+pub struct Upstream {
+    /// the remote name, or "." for a local upstream.
+    pub remote: String,
+    /// the full ref name on the remote.
+    pub merge: String,
+    /// our remote-tracking ref of it.
+    pub tracking: String,
+}
+
+fn buf_string(buf: Buf) -> Result<String, RebaseError> {
+    buf.as_str().map(str::to_owned)
+        .ok_or_else(|| RebaseError::InvalidRefName(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// The upstream of the local @branch (a full ref name).
+pub fn branch_upstream(repository: &Repository, branch: &str) -> Result<Upstream, RebaseError> {
+    let no_upstream = |e: git2::Error| {
+        if e.code() == ErrorCode::NotFound {
+            RebaseError::NoUpstream { branch: extract_name(branch).to_owned() }
+        } else {
+            e.into()
+        }
+    };
+
+    Ok(Upstream {
+        remote: buf_string(repository.branch_upstream_remote(branch).map_err(no_upstream)?)?,
+        merge: buf_string(repository.branch_upstream_merge(branch).map_err(no_upstream)?)?,
+        tracking: buf_string(repository.branch_upstream_name(branch).map_err(no_upstream)?)?,
+    })
+}
+
+/// For the remote-tracking @reference: which remote, and which ref there.
+pub fn tracking_source(repository: &Repository, reference: &str) -> Result<Upstream, RebaseError> {
+    let remote_name = buf_string(repository.branch_remote_name(reference)?)?;
+    let remote = repository.find_remote(&remote_name)?;
+
+    for refspec in remote.refspecs() {
+        if refspec.direction() == Direction::Fetch && refspec.dst_matches(reference) {
+            return Ok(Upstream {
+                merge: buf_string(refspec.rtransform(reference)?)?,
+                remote: remote_name,
+                tracking: reference.to_owned(),
+            });
+        }
+    }
+    Err(RebaseError::NoUpstream { branch: reference.to_owned() })
+}
+
+/// Update the remote-tracking ref of the @upstream.
+pub fn fetch(repository: &Repository, upstream: &Upstream) -> Result<(), RebaseError> {
+    if upstream.remote == LOCAL_REMOTE {
+        debug!("{} is local, nothing to fetch", upstream.tracking);
+        return Ok(());
+    }
+
+    let refspec = format!("+{}:{}", upstream.merge, upstream.tracking);
+    info!("fetch {} {}", upstream.remote, refspec);
+    repository.find_remote(&upstream.remote)?
+        .fetch(&[&refspec], None, Some("part of poset-rebasing"))?;
+    Ok(())
+}
+
+/// What to do with a local branch which has commits not in its upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DivergedPolicy {
+    /// fail, with a report.
+    #[default]
+    FfOnly,
+    /// replay the local commits on top of the upstream.
+    Rebase,
+    /// merge the upstream into the local branch.
+    Merge,
+    /// keep the local branch as it is, warn.
+    Leave,
+}
+
+impl FromStr for DivergedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ff-only" => Ok(DivergedPolicy::FfOnly),
+            "rebase" => Ok(DivergedPolicy::Rebase),
+            "merge" => Ok(DivergedPolicy::Merge),
+            "leave" => Ok(DivergedPolicy::Leave),
+            _ => Err(format!("invalid diverged policy {}, expected ff-only, rebase, merge or leave", s)),
+        }
+    }
+}
+
+impl DivergedPolicy {
+    /// The `branch.<name>.hierarchyDiverged' git config of the @branch, or `hierarchy.diverged'.
+    pub fn from_config(repository: &Repository, branch: &str) -> Result<Option<DivergedPolicy>, RebaseError> {
+        let config = repository.config()?;
+        let branch_key = format!("branch.{}.{}", extract_name(branch), BRANCH_DIVERGED_CONFIG);
+
+        for key in [branch_key.as_str(), DIVERGED_CONFIG] {
+            match config.get_string(key) {
+                Ok(value) => {
+                    return value.parse().map(Some)
+                        .map_err(|reason| RebaseError::InvalidConfig { key: key.to_owned(), value, reason });
+                }
+                Err(e) if e.code() == ErrorCode::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// What `update_from_upstream' did to the branch.
+#[derive(Debug, PartialEq)]
+pub enum UpdateOutcome {
+    UpToDate,
+    /// only local commits, nothing to take.
+    Ahead,
+    FastForwarded,
+    Rebased,
+    Merged,
+    /// diverged, and left alone.
+    Left { ahead: usize, behind: usize },
+}
+
+/// Bring the local @branch up to the (already fetched) @upstream, following the @policy
+/// when they have diverged.
+pub fn update_from_upstream(repository: &Repository,
+                            branch: &Reference<'_>,
+                            upstream: &Reference<'_>,
+                            policy: DivergedPolicy,
+) -> Result<UpdateOutcome, RebaseError> {
+    let branch_name = branch.name().unwrap();
+    let upstream_name = upstream.shorthand().unwrap();
+    let local = branch.peel_to_commit()?;
+    let remote = upstream.peel_to_commit()?;
+
+    let (ahead, behind) = repository.graph_ahead_behind(local.id(), remote.id())?;
+    debug!("{} is {} ahead, {} behind {}", branch_name, ahead, behind, upstream_name);
+
+    let new_oid = match (ahead, behind) {
+        (_, 0) => {
+            return Ok(if ahead == 0 { UpdateOutcome::UpToDate } else { UpdateOutcome::Ahead });
+        }
+        (0, _) => remote.id(),
+        _ => match policy {
+            DivergedPolicy::FfOnly => {
+                return Err(RebaseError::Diverged {
+                    branch: extract_name(branch_name).to_owned(),
+                    upstream: upstream_name.to_owned(),
+                    ahead,
+                    behind,
+                });
+            }
+            DivergedPolicy::Leave => {
+                warn!("{} has diverged from {}, leaving it", branch_name, upstream_name);
+                return Ok(UpdateOutcome::Left { ahead, behind });
+            }
+            DivergedPolicy::Rebase => rebase_onto(repository, branch_name, upstream_name, &local, &remote)?,
+            DivergedPolicy::Merge => merge_upstream(repository, branch_name, upstream_name, &local, &remote)?,
+        }
+    };
+
+    info!("updating {} to {}", branch_name, new_oid);
+    // we move the branch, the checkout must not follow it.
+    detach_head_from(repository, branch)?;
+    repository.reference(branch_name, new_oid, true, FETCH_REFLOG)?;
+
+    Ok(match (ahead, policy) {
+        (0, _) => UpdateOutcome::FastForwarded,
+        (_, DivergedPolicy::Rebase) => UpdateOutcome::Rebased,
+        _ => UpdateOutcome::Merged,
+    })
+}
+
+// in memory, the commits already upstream become empty and are dropped.
+// Merges are refused, before anything is done.
+fn rebase_onto(repository: &Repository,
+               branch_name: &str,
+               upstream_name: &str,
+               local: &Commit<'_>,
+               remote: &Commit<'_>,
+) -> Result<Oid, RebaseError> {
+    let mut walk = repository.revwalk()?;
+    walk.push(local.id())?;
+    walk.hide(remote.id())?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+    let commits = walk.map(|oid| repository.find_commit(oid?)).collect::<Result<Vec<_>, _>>()?;
+    if let Some(merge) = commits.iter().find(|c| c.parent_count() > 1) {
+        return Err(RebaseError::UpstreamMergeCommit {
+            branch: extract_name(branch_name).to_owned(),
+            upstream: upstream_name.to_owned(),
+            commit: merge.id(),
+        });
+    }
+
+    let mut parent = remote.clone();
+    for to_apply in commits {
+        let mut index = repository.cherrypick_commit(&to_apply, &parent, 0, None)?;
+        if index.has_conflicts() {
+            return Err(RebaseError::UpstreamConflict {
+                branch: extract_name(branch_name).to_owned(),
+                upstream: upstream_name.to_owned(),
+                action: "rebase",
+                paths: conflicted_paths(&index)?,
+            });
+        }
+
+        let tree_oid = index.write_tree_to(repository)?;
+        if tree_oid == parent.tree_id() {
+            debug!("{} is already upstream", to_apply.id());
+            continue;
+        }
+        let new_oid = repository.commit(None,
+                                        &to_apply.author(),
+                                        &to_apply.committer(),
+                                        to_apply.message().unwrap_or_default(),
+                                        &repository.find_tree(tree_oid)?,
+                                        &[&parent])?;
+        parent = repository.find_commit(new_oid)?;
+    }
+    Ok(parent.id())
+}
+
+fn merge_upstream(repository: &Repository,
+                  branch_name: &str,
+                  upstream_name: &str,
+                  local: &Commit<'_>,
+                  remote: &Commit<'_>,
+) -> Result<Oid, RebaseError> {
+    let mut index = repository.merge_commits(local, remote, None)?;
+    if index.has_conflicts() {
+        return Err(RebaseError::UpstreamConflict {
+            branch: extract_name(branch_name).to_owned(),
+            upstream: upstream_name.to_owned(),
+            action: "merge",
+            paths: conflicted_paths(&index)?,
+        });
+    }
+
+    let tree = repository.find_tree(index.write_tree_to(repository)?)?;
+    let signature = repository.signature()?;
+    let message = format!("Merge {} into {}", upstream_name, extract_name(branch_name));
+    Ok(repository.commit(None, &signature, &signature, &message, &tree, &[local, remote])?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{TempDir, init};
+    use git2::Signature;
+    use std::path::Path;
+
+    fn commit_file(repository: &Repository, parent: Option<&Commit<'_>>, file: &str, content: &str) -> Oid {
+        let workdir = repository.workdir().unwrap();
+        std::fs::write(workdir.join(file), content).unwrap();
+        let mut index = repository.index().unwrap();
+        index.add_path(Path::new(file)).unwrap();
+        let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("t", "t@t").unwrap();
+        let parents: Vec<&Commit<'_>> = parent.into_iter().collect();
+        repository.commit(None, &signature, &signature, file, &tree, &parents).unwrap()
+    }
+
+    #[test]
+    fn test_diverged() {
+        let dir = TempDir::new("diverged");
+        let repository = init(dir.path());
+
+        let base = commit_file(&repository, None, "a", "a");
+        let base = repository.find_commit(base).unwrap();
+        repository.set_head_detached(base.id()).unwrap();
+        let remote = commit_file(&repository, Some(&base), "b", "upstream");
+        let local = commit_file(&repository, Some(&base), "c", "local");
+
+        let reset = |oid| repository.reference("refs/heads/work", oid, true, "test").unwrap();
+        let upstream = repository.reference("refs/remotes/origin/work", remote, true, "test").unwrap();
+
+        assert_eq!(update_from_upstream(&repository, &reset(remote), &upstream, DivergedPolicy::FfOnly).unwrap(),
+                   UpdateOutcome::UpToDate);
+        assert_eq!(update_from_upstream(&repository, &reset(base.id()), &upstream, DivergedPolicy::FfOnly).unwrap(),
+                   UpdateOutcome::FastForwarded);
+        assert!(matches!(update_from_upstream(&repository, &reset(local), &upstream, DivergedPolicy::FfOnly),
+                         Err(RebaseError::Diverged { ahead: 1, behind: 1, .. })));
+        assert_eq!(update_from_upstream(&repository, &reset(local), &upstream, DivergedPolicy::Leave).unwrap(),
+                   UpdateOutcome::Left { ahead: 1, behind: 1 });
+
+        assert_eq!(update_from_upstream(&repository, &reset(local), &upstream, DivergedPolicy::Rebase).unwrap(),
+                   UpdateOutcome::Rebased);
+        let rebased = repository.find_reference("refs/heads/work").unwrap().peel_to_commit().unwrap();
+        assert_eq!(rebased.parent_id(0).unwrap(), remote);
+
+        assert_eq!(update_from_upstream(&repository, &reset(local), &upstream, DivergedPolicy::Merge).unwrap(),
+                   UpdateOutcome::Merged);
+        let merged = repository.find_reference("refs/heads/work").unwrap().peel_to_commit().unwrap();
+        assert_eq!(merged.parent_ids().collect::<Vec<_>>(), vec![local, remote]);
+
+        // a local merge is not rebased, the branch stays.
+        let side = commit_file(&repository, Some(&base), "d", "side");
+        let local = repository.find_commit(local).unwrap();
+        let signature = repository.signature().unwrap();
+        let local_merge = repository.commit(None, &signature, &signature, "merge side", &local.tree().unwrap(),
+                                            &[&local, &repository.find_commit(side).unwrap()]).unwrap();
+        assert!(matches!(update_from_upstream(&repository, &reset(local_merge), &upstream, DivergedPolicy::Rebase),
+                         Err(RebaseError::UpstreamMergeCommit { commit, .. }) if commit == local_merge));
+        assert_eq!(repository.refname_to_id("refs/heads/work").unwrap(), local_merge);
+    }
+
+    #[test]
+    fn test_upstream() {
+        let dir = TempDir::new("upstream");
+        let remote_path = dir.join("remote");
+        let remote_repository = init(&remote_path);
+        let oid = commit_file(&remote_repository, None, "a", "a");
+        remote_repository.reference("refs/heads/team/feature", oid, true, "test").unwrap();
+
+        let repository = init(&dir.join("local"));
+        // slashes everywhere.
+        repository.remote("up/stream", remote_path.to_str().unwrap()).unwrap();
+        let mut config = repository.config().unwrap();
+        config.set_str("branch.work.remote", "up/stream").unwrap();
+        config.set_str("branch.work.merge", "refs/heads/team/feature").unwrap();
+
+        let upstream = branch_upstream(&repository, "refs/heads/work").unwrap();
+        assert_eq!(upstream, Upstream {
+            remote: "up/stream".to_owned(),
+            merge: "refs/heads/team/feature".to_owned(),
+            tracking: "refs/remotes/up/stream/team/feature".to_owned(),
+        });
+        assert_eq!(tracking_source(&repository, &upstream.tracking).unwrap(), upstream);
+        assert!(matches!(branch_upstream(&repository, "refs/heads/other"),
+                         Err(RebaseError::NoUpstream { .. })));
+
+        fetch(&repository, &upstream).unwrap();
+        assert_eq!(repository.refname_to_id(&upstream.tracking).unwrap(), oid);
+
+        config.set_str(DIVERGED_CONFIG, "sideways").unwrap();
+        assert!(matches!(DivergedPolicy::from_config(&repository, "refs/heads/work"),
+                         Err(RebaseError::InvalidConfig { key, value, .. })
+                         if key == DIVERGED_CONFIG && value == "sideways"));
+        config.set_str("branch.work.hierarchyDiverged", "merge").unwrap();
+        assert_eq!(DivergedPolicy::from_config(&repository, "refs/heads/work").unwrap(), Some(DivergedPolicy::Merge));
+    }
+}
@@ -21,10 +21,10 @@ use crate::collected::{try_collect};
 use git2::{Commit, Oid, Reference, Repository, Revwalk, Sort, Error};
 
 // low level sum & segment
-const SEGMENT_BASE_PATTERN: &str = "refs/base/";
-const SEGMENT_START_PATTERN: &str = "refs/start/";
-const SUM_SUMMAND_PATTERN: &str = "refs/sums/";
-const SEPARATOR : &str = "/";
+pub(crate) const SEGMENT_BASE_PATTERN: &str = "refs/base/";
+pub(crate) const SEGMENT_START_PATTERN: &str = "refs/start/";
+pub(crate) const SUM_SUMMAND_PATTERN: &str = "refs/sums/";
+pub(crate) const SEPARATOR : &str = "/";
 
 #[inline]
 // can we return Cow<&str, String> ?
@@ -46,15 +46,40 @@ pub fn sum_fmt(s: &str) -> colored::ColoredString //  maybe a method?
 }
 
 
-fn base_name(name: &str) -> String {
+pub(crate) fn head_name(name: &str) -> String {
+    concatenate(GIT_HEADS_PATTERN, name)
+}
+
+pub(crate) fn base_name(name: &str) -> String {
     concatenate(SEGMENT_BASE_PATTERN, name)
 }
 
-fn start_name(name: &str) -> String {
+pub(crate) fn start_name(name: &str) -> String {
     concatenate(SEGMENT_START_PATTERN, name)
 }
 
-fn sum_summands<'repo>(repository: &'repo Repository, name: &str) -> Vec<Reference<'repo>> {
+/// None if there's no such ref.
+pub(crate) fn optional_id(repository: &Repository, name: &str) -> Result<Option<Oid>, Error> {
+    match repository.refname_to_id(name) {
+        Ok(oid) => Ok(Some(oid)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// "refs/sums/name/3" -> 3
+fn summand_index(summand_name: &str, sum_name: &str) -> Option<usize> {
+    summand_name.strip_prefix(SUM_SUMMAND_PATTERN)?
+        .strip_prefix(sum_name)?
+        .strip_prefix(SEPARATOR)?
+        .parse().ok()
+}
+
+pub(crate) fn summand_ref_name(sum_name: &str, index: usize) -> String {
+    concatenate(SUM_SUMMAND_PATTERN, sum_name) + SEPARATOR + &index.to_string()
+}
+
+pub(crate) fn sum_summands<'repo>(repository: &'repo Repository, name: &str) -> Vec<Reference<'repo>> {
     let mut v = Vec::new();
 
     debug!("searching for sum {}", name);
@@ -65,6 +90,8 @@ fn sum_summands<'repo>(repository: &'repo Repository, name: &str) -> Vec<Referen
             v.push(r.unwrap());
         }
     }
+    // not lexicographic: 10 after 9
+    v.sort_by_key(|r| summand_index(r.name().unwrap(), name));
 
     v
 }
@@ -260,6 +287,46 @@ impl<'repo> Segment<'repo> {
                     .expect("new base"));
         debug!("old base pointed at {:?}", _old.name().unwrap());
     }
+
+    /// `set_base' & `set_start' together, fallible: on failure neither is changed.
+    pub fn move_to(&self, repository: &'repo Repository, new_base: &'_ Reference<'_>, start: Oid) -> Result<(), Error> {
+        let old_base = self.base.borrow().symbolic_target().expect("base should be a symbolic reference").to_owned();
+        let base = self.base.borrow_mut().symbolic_set_target(new_base.name().expect("provided reference must have name"),
+                                                              "Changing base")?;
+        self.base.replace(base);
+
+        let moved = repository.find_reference(self._start.name().unwrap())
+            .and_then(|mut start_ref| start_ref.set_target(start, REBASED_REFLOG));
+        if let Err(e) = moved {
+            warn!("failed to set the start of {}, restoring the base {}", self.name(), old_base);
+            let base = self.base.borrow_mut().symbolic_set_target(&old_base, "Restoring base")?;
+            self.base.replace(base);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Point sum/name/1 ... at @kept, then delete the rest of @old, the (name, target) pairs.
+/// Renumbering first, so that a failure leaves no gaps.
+fn renumber_summands(
+    repository: &Repository,
+    sum_name: &str,
+    old: &[(String, String)],
+    kept: &[&str],
+) -> Result<(), Error> {
+    for (n, target) in kept.iter().enumerate() {
+        if old[n].1 != *target {
+            debug!("renumber {} -> {}", target, summand_ref_name(sum_name, n + 1));
+            repository.reference_symbolic(&summand_ref_name(sum_name, n + 1), target, true, "renumber")?;
+        }
+    }
+    // from the end, so those left are still 1..M
+    for (name, target) in old[kept.len()..].iter().rev() {
+        info!("removing summand {} ({})", name, target);
+        repository.find_reference(name)?.delete()?;
+    }
+    Ok(())
 }
 
 /// create "numbered" symbolic references pointing at the summands.
@@ -277,11 +344,7 @@ where 'repo : 'a {
             |(n, s)| {
                 // this starts from 0
                 repository.reference_symbolic(
-                    &(SUM_SUMMAND_PATTERN.to_string()
-                      + SEPARATOR
-                      + sum_name
-                      + SEPARATOR
-                      + &(counter_start + 1 + n).to_string()),
+                    &summand_ref_name(sum_name, counter_start + 1 + n),
                     // mmc: this panics! todo: Avoid that!
                     s.name().expect("should have name"),
                     false,
@@ -289,7 +352,7 @@ where 'repo : 'a {
             }));
 
     match summands.branch() {
-        ControlFlow::Continue(v) => return Ok(v),
+        ControlFlow::Continue(v) => Ok(v),
         ControlFlow::Break(res) => {
             // res cannot be Infallible
             for mut reference in res.unwrap_err()  {
@@ -368,10 +431,8 @@ impl<'repo> Sum<'repo> {
         let mut max = 0;
         for i in summands {
             eprintln!("summand {}", i.name().unwrap());
-            let n = i.name().expect("must have name").strip_prefix(SUM_SUMMAND_PATTERN).expect("must have SUM prefix")
-                .strip_prefix(&self.name).expect("owned by the sum")
-                .strip_prefix("/").expect("/");
-            let index = n.parse::<isize>().expect("should be numeric");
+            let index = summand_index(i.name().expect("must have name"), &self.name)
+                .expect("should be numeric, owned by the sum");
             // eprintln!("summand {}", index);
             if max < index {
                 max = index;
@@ -380,9 +441,61 @@ impl<'repo> Sum<'repo> {
         let mut new_summands = create_summand_refs(repository, &self.name, max, components)?;
 
         self.summands.append(&mut new_summands);
-        return Ok(());
+        Ok(())
+    }
+
+
+    /// Drop the summands pointing at @components, and renumber the rest 1..N,
+    /// keeping their order.  At least 2 must remain.  On failure all are restored.
+    pub fn remove_summands<'a>(
+        &mut self,
+        repository: &'repo Repository,
+        components: impl Iterator<Item = &'a Reference<'repo>>,
+    ) -> Result<(), Error>
+    where 'repo : 'a {
+        let old: Vec<(String, String)> = self.summands.iter()
+            .map(|s| (s.name().unwrap().to_owned(),
+                      s.symbolic_target().expect("summand should be symbolic").to_owned()))
+            .collect();
+        let mut kept: Vec<&str> = old.iter().map(|(_, target)| target.as_str()).collect();
+        for component in components {
+            let target = component.name().expect("should have name");
+            let position = kept.iter()
+                .position(|t| *t == target)
+                .ok_or_else(|| Error::from_str(&format!("{} is not a summand of {}", target, self.name)))?;
+            kept.remove(position);
+        }
+
+        // one parent is not a merge.
+        if kept.len() < 2 {
+            return Err(Error::from_str(
+                &format!("{} would have less than 2 summands, delete it instead: git-sum delete {}",
+                         self.name, self.name)));
+        }
+
+        if let Err(e) = renumber_summands(repository, &self.name, &old, &kept) {
+            warn!("failed to remove summands of {}, restoring them", self.name);
+            for (name, target) in &old {
+                repository.reference_symbolic(name, target, true, "restore")?;
+            }
+            return Err(e);
+        }
+        self.summands = sum_summands(repository, &self.name);
+        Ok(())
     }
 
+    /// Point the summand of @old at @new, keeping its position.
+    pub fn replace_summand(&mut self, old: &Reference<'repo>, new: &Reference<'repo>) -> Result<(), Error> {
+        let target = old.name().expect("should have name");
+        let position = self.summands.iter()
+            .position(|s| s.symbolic_target() == Some(target))
+            .ok_or_else(|| Error::from_str(&format!("{} is not a summand of {}", target, self.name)))?;
+
+        let new_target = new.name().expect("should have name");
+        info!("replacing summand {} with {}", target, new_target);
+        self.summands[position] = self.summands[position].symbolic_set_target(new_target, "replace summand")?;
+        Ok(())
+    }
 
     // todo: iterator?
     pub fn summands(&self, repository: &'repo Repository) -> Vec<Reference<'repo>> {
@@ -426,7 +539,7 @@ pub enum GitHierarchy<'repo> {
 }
 
 impl<'repo> GitHierarchy<'repo> {
-    pub fn commit(&self) -> Result<Commit<'_>, git2::Error> {
+    pub fn commit(&self) -> Result<Commit<'repo>, git2::Error> {
         let reference: &Reference<'_> = match &self {
             GitHierarchy::Name(x) => {
                 eprintln!("trying {x}");
@@ -483,3 +596,46 @@ impl<'a> NodeExpander for GitHierarchy<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summand_index() {
+        assert_eq!(summand_index("refs/sums/top/3", "top"), Some(3));
+        assert_eq!(summand_index("refs/sums/top/12", "top"), Some(12));
+        assert_eq!(summand_index("refs/sums/topic/1", "top"), None);
+        assert_eq!(summand_index(&summand_ref_name("a/b", 7), "a/b"), Some(7));
+    }
+
+    #[test]
+    fn test_remove_summands() {
+        use crate::testing::{TempDir, empty_commit, init};
+
+        let dir = TempDir::new("remove-summands");
+        let repository = init(dir.path());
+        let commit = empty_commit(&repository, "first", &[]);
+        for name in ["a", "b", "c", "top"] {
+            repository.reference(&head_name(name), commit, false, "test").unwrap();
+        }
+        for (n, name) in ["a", "b", "c"].iter().enumerate() {
+            repository.reference_symbolic(&summand_ref_name("top", n + 1), &head_name(name), false, "test").unwrap();
+        }
+        let GitHierarchy::Sum(mut sum) = load(&repository, "top").unwrap() else { panic!("not a sum") };
+
+        let b = repository.find_reference(&head_name("b")).unwrap();
+        sum.remove_summands(&repository, std::iter::once(&b)).unwrap();
+        let targets = |repository: &Repository| -> Vec<String> {
+            sum_summands(repository, "top").iter().map(|s| s.symbolic_target().unwrap().to_owned()).collect()
+        };
+        assert_eq!(targets(&repository), [head_name("a"), head_name("c")]);
+        assert!(repository.find_reference(&summand_ref_name("top", 3)).is_err());
+
+        // not a summand anymore, and too few would be left: nothing changes.
+        assert!(sum.remove_summands(&repository, std::iter::once(&b)).is_err());
+        let a = repository.find_reference(&head_name("a")).unwrap();
+        assert!(sum.remove_summands(&repository, std::iter::once(&a)).is_err());
+        assert_eq!(targets(&repository), [head_name("a"), head_name("c")]);
+    }
+}
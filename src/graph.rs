@@ -1,5 +1,7 @@
 pub mod discover;
 pub mod discover_pet;
+pub mod export;
+pub mod schedule;
 pub mod topology_sort;
 use crate::graph::topology_sort::topological_sort;
 
@@ -0,0 +1,183 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// The hierarchy as Graphviz DOT or Mermaid, for docs & reviews.
+// First flattened into `ExportGraph', which the renderers turn into text.
+
+use git2::Repository;
+
+use std::fmt::Write;
+
+use crate::git_hierarchy::GitHierarchy;
+use crate::graph::discover_pet::HierarchyGraph;
+use crate::rebase::check_sum;
+use crate::utils::extract_name;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Segment,
+    Sum,
+    Reference,
+}
+
+#[derive(Debug)]
+pub struct ExportNode {
+    pub label: String,
+    pub kind: NodeKind,
+    /// needs a rebase / re-merge, itself or because something below it does.
+    pub stale: bool,
+}
+
+/// indices into `ExportGraph::nodes'
+#[derive(Debug, PartialEq)]
+pub struct ExportEdge {
+    pub from: usize,
+    pub to: usize,
+    pub label: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ExportGraph {
+    pub nodes: Vec<ExportNode>,
+    pub edges: Vec<ExportEdge>,
+}
+
+impl ExportGraph {
+    /// Nodes in the discovery order, edges from a node to its base/summands.
+    pub fn new<'repo>(repository: &'repo Repository, hierarchy: &HierarchyGraph<'repo>) -> ExportGraph {
+        let objects = &hierarchy.labeled_objects;
+        let position = |name: &str| hierarchy.discovery_order.iter().position(|n| n == name)
+            .expect("should be discovered");
+
+        let mut graph = ExportGraph::default();
+        for (from, name) in hierarchy.discovery_order.iter().enumerate() {
+            let (kind, stale) = match objects.get(name).expect("should be loaded") {
+                GitHierarchy::Name(_) => {
+                    panic!("unresolved node");
+                }
+                GitHierarchy::Segment(segment) => {
+                    let base = segment.base(repository);
+                    graph.edges.push(ExportEdge {
+                        from,
+                        to: position(base.name().unwrap()),
+                        label: "base".to_owned(),
+                    });
+                    (NodeKind::Segment, !segment.uptodate(repository))
+                }
+                GitHierarchy::Sum(sum) => {
+                    for (n, summand) in sum.summands(repository).iter().enumerate() {
+                        graph.edges.push(ExportEdge {
+                            from,
+                            to: position(summand.name().unwrap()),
+                            label: format!("summand {}", n + 1),
+                        });
+                    }
+                    (NodeKind::Sum, check_sum(repository, sum, objects).is_err())
+                }
+                GitHierarchy::Reference(_) => (NodeKind::Reference, false),
+            };
+            graph.nodes.push(ExportNode { label: extract_name(name).to_owned(), kind, stale });
+        }
+        graph.propagate_stale();
+        graph
+    }
+
+    // up the edges: rebasing a base moves what is built on it.
+    fn propagate_stale(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for edge in &self.edges {
+                if self.nodes[edge.to].stale && !self.nodes[edge.from].stale {
+                    self.nodes[edge.from].stale = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph hierarchy {\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let shape = match node.kind {
+                NodeKind::Segment => "box",
+                NodeKind::Sum => "ellipse",
+                NodeKind::Reference => "plaintext",
+            };
+            let stale = if node.stale { ", style=filled, fillcolor=\"#ffcccc\", color=red" } else { "" };
+            writeln!(out, "  n{} [label=\"{}\", shape={}{}];", i, node.label.replace('"', "\\\""), shape, stale).unwrap();
+        }
+        for edge in &self.edges {
+            writeln!(out, "  n{} -> n{} [label=\"{}\"];", edge.from, edge.to, edge.label).unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("flowchart TB\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let label = node.label.replace('"', "#quot;");
+            match node.kind {
+                NodeKind::Segment => writeln!(out, "  n{}[\"{}\"]", i, label),
+                NodeKind::Sum => writeln!(out, "  n{}([\"{}\"])", i, label),
+                NodeKind::Reference => writeln!(out, "  n{}[/\"{}\"/]", i, label),
+            }.unwrap();
+        }
+        for edge in &self.edges {
+            writeln!(out, "  n{} -->|{}| n{}", edge.from, edge.label, edge.to).unwrap();
+        }
+
+        let stale: Vec<_> = self.nodes.iter().enumerate()
+            .filter(|(_, node)| node.stale)
+            .map(|(i, _)| format!("n{}", i))
+            .collect();
+        if !stale.is_empty() {
+            out.push_str("  classDef stale fill:#fcc,stroke:#c00\n");
+            writeln!(out, "  class {} stale", stale.join(",")).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> ExportGraph {
+        let node = |label: &str, kind, stale| ExportNode { label: label.to_owned(), kind, stale };
+        let edge = |from, to, label: &str| ExportEdge { from, to, label: label.to_owned() };
+        ExportGraph {
+            nodes: vec![node("master", NodeKind::Reference, false),
+                        node("seg", NodeKind::Segment, true),
+                        node("top", NodeKind::Sum, false)],
+            edges: vec![edge(1, 0, "base"), edge(2, 1, "summand 1")],
+        }
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = sample().to_dot();
+        assert!(dot.starts_with("digraph hierarchy {\n"));
+        assert!(dot.contains("  n0 [label=\"master\", shape=plaintext];\n"));
+        assert!(dot.contains("  n1 [label=\"seg\", shape=box, style=filled"));
+        assert!(dot.contains("  n2 -> n1 [label=\"summand 1\"];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let mermaid = sample().to_mermaid();
+        assert!(mermaid.starts_with("flowchart TB\n"));
+        assert!(mermaid.contains("  n2([\"top\"])\n"));
+        assert!(mermaid.contains("  n1 -->|base| n0\n"));
+        assert!(mermaid.ends_with("  class n1 stale\n"));
+    }
+
+    #[test]
+    fn test_propagate_stale() {
+        let mut graph = sample();
+        graph.propagate_stale();
+        let stale: Vec<_> = graph.nodes.iter().map(|node| node.stale).collect();
+        assert_eq!(stale, vec![false, true, true]);
+    }
+}
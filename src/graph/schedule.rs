@@ -0,0 +1,100 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// --jobs: hand out the nodes as soon as everything they depend on is done.
+// Nodes are the indices into the discovery order.
+
+use std::collections::VecDeque;
+
+use crate::graph::discover_pet::HierarchyGraph;
+
+#[derive(Debug)]
+pub struct Schedule {
+    /// how many dependencies are not done yet.
+    waiting_for: Vec<usize>,
+    dependents: Vec<Vec<usize>>,
+    ready: VecDeque<usize>,
+    running: usize,
+}
+
+impl Schedule {
+    /// @edges: (dependency, dependent) pairs.
+    pub fn new(count: usize, edges: impl Iterator<Item = (usize, usize)>) -> Schedule {
+        let mut waiting_for = vec![0; count];
+        let mut dependents = vec![Vec::new(); count];
+        for (dependency, dependent) in edges {
+            waiting_for[dependent] += 1;
+            dependents[dependency].push(dependent);
+        }
+
+        let ready = (0..count).filter(|i| waiting_for[*i] == 0).collect();
+        Schedule { waiting_for, dependents, ready, running: 0 }
+    }
+
+    /// The edges of the petgraph, whichever way they point: the discovery order
+    /// is a post-order, so the base/summand is always the one found first.
+    pub fn from_graph(hierarchy_graph: &HierarchyGraph<'_>) -> Schedule {
+        let order = &hierarchy_graph.discovery_order;
+        let position = |index| {
+            let label = &hierarchy_graph.graph[index];
+            order.iter().position(|v| v == label).unwrap()
+        };
+
+        let edges = hierarchy_graph.graph.edge_indices()
+            .map(|edge| {
+                let (a, b) = hierarchy_graph.graph.edge_endpoints(edge).unwrap();
+                let (a, b) = (position(a), position(b));
+                if a < b { (a, b) } else { (b, a) }
+            });
+        Schedule::new(order.len(), edges)
+    }
+
+    /// A node which can start now.
+    pub fn start_next(&mut self) -> Option<usize> {
+        let node = self.ready.pop_front()?;
+        self.running += 1;
+        Some(node)
+    }
+
+    /// @node is done, its dependents might become ready.
+    pub fn done(&mut self, node: usize) {
+        self.running -= 1;
+        for dependent in &self.dependents[node] {
+            self.waiting_for[*dependent] -= 1;
+            if self.waiting_for[*dependent] == 0 {
+                self.ready.push_back(*dependent);
+            }
+        }
+    }
+
+    /// @node failed: its dependents never start.
+    pub fn failed(&mut self, _node: usize) {
+        self.running -= 1;
+    }
+
+    pub fn running(&self) -> usize {
+        self.running
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        // 0 <- 1 <- 3, 0 <- 2 <- 3
+        let mut schedule = Schedule::new(4, [(0, 1), (0, 2), (1, 3), (2, 3)].into_iter());
+        assert_eq!(schedule.start_next(), Some(0));
+        assert_eq!(schedule.start_next(), None);
+        schedule.done(0);
+        assert_eq!(schedule.start_next(), Some(1));
+        assert_eq!(schedule.start_next(), Some(2));
+        assert_eq!(schedule.running(), 2);
+        schedule.done(2);
+        assert_eq!(schedule.start_next(), None);
+        schedule.done(1);
+        assert_eq!(schedule.start_next(), Some(3));
+        schedule.failed(3);
+        assert_eq!(schedule.running(), 0);
+    }
+}
@@ -13,7 +13,7 @@ pub fn topological_sort(graph: &[Vec<usize>]) -> Option<Vec<usize>> {
 
     // Calculate in-degrees for each vertex
     let mut in_degree = vec![0; n];
-    for (n, neighbors) in graph.iter().enumerate() {
+    for neighbors in graph {
         for &v in neighbors {
             if v < n {
                 // Bounds check
@@ -153,6 +153,18 @@ mod tests {
         assert!(is_valid_topological_order(&graph, &result));
     }
 
+    #[test]
+    fn test_edge_to_higher_vertex() {
+        // 0 -> 1, 2 -> 0: the in-degree of 1 counts too.
+        let graph = vec![
+            vec![1], // 0 -> 1
+            vec![],  // 1 -> nothing
+            vec![0], // 2 -> 0
+        ];
+
+        assert_eq!(topological_sort(&graph).unwrap(), vec![2, 0, 1]);
+    }
+
     fn is_valid_topological_order(graph: &[Vec<usize>], order: &[usize]) -> bool {
         let mut position = vec![0; graph.len()];
         for (i, &vertex) in order.iter().enumerate() {
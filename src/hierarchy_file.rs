@@ -0,0 +1,363 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// A hierarchy as a TOML file: `git-hierarchy export' writes it, `apply'
+// makes the refs match it.  Only the topology -- bases & summands -- is
+// applied to existing segments and sums; their start & head are just
+// compared, we never move a branch from a file.
+
+use git2::{Oid, Reference, Repository};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::fmt;
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::git_hierarchy::{GitHierarchy, Segment, Sum, head_name, load, optional_id};
+use crate::rebase::RebaseError;
+use crate::share::{Definition, local_definition};
+use crate::utils::{extract_name, serde_oid, short};
+
+pub const FILE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentEntry {
+    pub name: String,
+    /// full ref name.
+    pub base: String,
+    #[serde(with = "serde_oid")]
+    pub start: Oid,
+    #[serde(with = "serde_oid")]
+    pub head: Oid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SumEntry {
+    pub name: String,
+    /// full ref names, in order.
+    pub summands: Vec<String>,
+    #[serde(with = "serde_oid")]
+    pub head: Oid,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct HierarchyFile {
+    pub version: u32,
+    /// base first.
+    #[serde(default, rename = "segment")]
+    pub segments: Vec<SegmentEntry>,
+    #[serde(default, rename = "sum")]
+    pub sums: Vec<SumEntry>,
+}
+
+impl HierarchyFile {
+    pub fn new(definitions: impl Iterator<Item = Definition>) -> HierarchyFile {
+        let mut file = HierarchyFile { version: FILE_VERSION, segments: Vec::new(), sums: Vec::new() };
+        for definition in definitions {
+            match definition {
+                Definition::Segment { name, base, start, head } =>
+                    file.segments.push(SegmentEntry { name, base, start, head }),
+                Definition::Sum { name, summands, head } =>
+                    file.sums.push(SumEntry { name, summands, head }),
+            }
+        }
+        file
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("should serialize")
+    }
+
+    pub fn from_toml(text: &str) -> Result<HierarchyFile, String> {
+        let file: HierarchyFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        if file.version != FILE_VERSION {
+            return Err(format!("unsupported version {}, expected {}", file.version, FILE_VERSION));
+        }
+        Ok(file)
+    }
+}
+
+/// The definitions of the segments & sums among the @nodes.
+pub fn export<'repo, 'a>(repository: &'repo Repository,
+                         nodes: impl Iterator<Item = &'a GitHierarchy<'repo>>,
+) -> Result<HierarchyFile, RebaseError>
+where
+    'repo: 'a,
+{
+    let mut definitions = Vec::new();
+    for node in nodes {
+        let name = match node {
+            GitHierarchy::Segment(segment) => segment.name(),
+            GitHierarchy::Sum(sum) => sum.name(),
+            _ => continue,
+        };
+        definitions.extend(local_definition(repository, name)?);
+    }
+    Ok(HierarchyFile::new(definitions.into_iter()))
+}
+
+/// What `apply' did, or refused to do, to one segment or sum.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Created,
+    Unchanged,
+    Rebased { from: String, to: String },
+    SummandsAdded(Vec<String>),
+    SummandsRemoved(Vec<String>),
+    /// the file differs, but we keep ours.
+    Kept(String),
+    /// nothing done.
+    Conflict(String),
+}
+
+impl Change {
+    pub fn conflict(&self) -> bool {
+        matches!(self, Change::Conflict(_))
+    }
+}
+
+fn short_names(names: &[String]) -> String {
+    names.iter().map(|s| extract_name(s)).collect::<Vec<_>>().join(", ")
+}
+
+// like a diff.
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Created => write!(f, "+ created"),
+            Change::Unchanged => write!(f, "= unchanged"),
+            Change::Rebased { from, to } =>
+                write!(f, "~ base {} -> {}", extract_name(from), extract_name(to)),
+            Change::SummandsAdded(names) => write!(f, "+ summands {}", short_names(names)),
+            Change::SummandsRemoved(names) => write!(f, "- summands {}", short_names(names)),
+            Change::Kept(what) => write!(f, "  kept {}", what),
+            Change::Conflict(what) => write!(f, "! {}", what),
+        }
+    }
+}
+
+fn kept_head(local: Oid, file: Oid) -> Option<Change> {
+    (local != file).then(|| Change::Kept(format!("head {} (file: {})", short(local), short(file))))
+}
+
+fn apply_segment(repository: &Repository, entry: &SegmentEntry) -> Result<Vec<Change>, RebaseError> {
+    let base = repository.find_reference(&entry.base)?;
+
+    match local_definition(repository, &entry.name)? {
+        None => {
+            if let Some(oid) = optional_id(repository, &head_name(&entry.name))? {
+                return Ok(vec![Change::Conflict(format!("a plain branch at {}", short(oid)))]);
+            }
+            Segment::create(repository, &entry.name, &base, entry.start, entry.head)?;
+            Ok(vec![Change::Created])
+        }
+        Some(Definition::Sum { .. }) => Ok(vec![Change::Conflict("a sum here".to_owned())]),
+        Some(Definition::Segment { base: local_base, start, head, .. }) => {
+            let mut changes = Vec::new();
+            if local_base != entry.base {
+                let GitHierarchy::Segment(segment) = load(repository, &entry.name)? else {
+                    unreachable!("just found the segment");
+                };
+                segment.set_base(repository, &base);
+                changes.push(Change::Rebased { from: local_base, to: entry.base.clone() });
+            }
+            if start != entry.start {
+                changes.push(Change::Kept(format!("start {} (file: {})", short(start), short(entry.start))));
+            }
+            changes.extend(kept_head(head, entry.head));
+            if changes.is_empty() {
+                changes.push(Change::Unchanged);
+            }
+            Ok(changes)
+        }
+    }
+}
+
+fn apply_sum(repository: &Repository, entry: &SumEntry) -> Result<Vec<Change>, RebaseError> {
+    let summands = entry.summands.iter()
+        .map(|name| repository.find_reference(name))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match local_definition(repository, &entry.name)? {
+        None => {
+            if let Some(oid) = optional_id(repository, &head_name(&entry.name))? {
+                return Ok(vec![Change::Conflict(format!("a plain branch at {}", short(oid)))]);
+            }
+            Sum::create(repository, &entry.name, summands.iter(), Some(repository.find_commit(entry.head)?))?;
+            Ok(vec![Change::Created])
+        }
+        Some(Definition::Segment { .. }) => Ok(vec![Change::Conflict("a segment here".to_owned())]),
+        Some(Definition::Sum { summands: local_summands, head, .. }) => {
+            let GitHierarchy::Sum(mut sum) = load(repository, &entry.name)? else {
+                unreachable!("just found the sum");
+            };
+            let mut changes = Vec::new();
+
+            let added: Vec<&Reference<'_>> = summands.iter()
+                .filter(|s| !local_summands.iter().any(|l| Some(l.as_str()) == s.name()))
+                .collect();
+            if !added.is_empty() {
+                sum.add_summands(repository, added.iter().copied(), None)?;
+                changes.push(Change::SummandsAdded(added.iter().map(|s| s.name().unwrap().to_owned()).collect()));
+            }
+
+            let removed: Vec<Reference<'_>> = local_summands.iter()
+                .filter(|l| !entry.summands.contains(l))
+                .map(|l| repository.find_reference(l))
+                .collect::<Result<_, _>>()?;
+            if !removed.is_empty() {
+                sum.remove_summands(repository, removed.iter())?;
+                changes.push(Change::SummandsRemoved(removed.iter().map(|s| s.name().unwrap().to_owned()).collect()));
+            }
+
+            if let Some(Definition::Sum { summands: now, .. }) = local_definition(repository, &entry.name)?
+                && now != entry.summands
+            {
+                changes.push(Change::Kept(format!("the summand order {}", short_names(&now))));
+            }
+            changes.extend(kept_head(head, entry.head));
+            if changes.is_empty() {
+                changes.push(Change::Unchanged);
+            }
+            Ok(changes)
+        }
+    }
+}
+
+enum Entry<'f> {
+    Segment(&'f SegmentEntry),
+    Sum(&'f SumEntry),
+}
+
+impl Entry<'_> {
+    fn name(&self) -> &str {
+        match self {
+            Entry::Segment(segment) => &segment.name,
+            Entry::Sum(sum) => &sum.name,
+        }
+    }
+
+    fn dependencies(&self) -> &[String] {
+        match self {
+            Entry::Segment(segment) => std::slice::from_ref(&segment.base),
+            Entry::Sum(sum) => &sum.summands,
+        }
+    }
+}
+
+/// Make the local segments & sums match the @file.  An entry waits until its
+/// base/summands exist, i.e. might be created by another entry.
+pub fn apply(repository: &Repository, file: &HierarchyFile) -> Result<Vec<(String, Vec<Change>)>, RebaseError> {
+    let mut pending: Vec<Entry<'_>> = file.segments.iter().map(Entry::Segment)
+        .chain(file.sums.iter().map(Entry::Sum))
+        .collect();
+
+    // before changing anything.
+    let defined: HashSet<String> = pending.iter().map(|e| head_name(e.name())).collect();
+    for dependency in pending.iter().flat_map(|e| e.dependencies()) {
+        if !defined.contains(dependency)
+            && let Err(source) = repository.find_reference(dependency)
+        {
+            return Err(RebaseError::MissingReference { name: dependency.clone(), source });
+        }
+    }
+
+    let mut changes = Vec::new();
+    while !pending.is_empty() {
+        let before = pending.len();
+        let mut waiting = Vec::new();
+        for entry in pending {
+            if let Some(dependency) = entry.dependencies().iter().find(|d| repository.find_reference(d).is_err()) {
+                debug!("{} waits for {}", entry.name(), dependency);
+                waiting.push(entry);
+                continue;
+            }
+
+            let change = match &entry {
+                Entry::Segment(segment) => apply_segment(repository, segment)?,
+                Entry::Sum(sum) => apply_sum(repository, sum)?,
+            };
+            changes.push((entry.name().to_owned(), change));
+        }
+
+        if waiting.len() == before {
+            let names = waiting.iter().map(|e| e.name()).collect::<Vec<_>>().join(", ");
+            return Err(RebaseError::WrongHierarchy(format!("a cycle among {}", names)));
+        }
+        pending = waiting;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{TempDir, empty_commit, init};
+
+    #[test]
+    fn test_export_apply() {
+        let dir = TempDir::new("file");
+        let repository = init(dir.path());
+
+        let first = empty_commit(&repository, "first", &[]);
+        let second = empty_commit(&repository, "second", &[first]);
+        repository.reference("refs/heads/main", first, true, "test").unwrap();
+        repository.reference("refs/heads/side", first, true, "test").unwrap();
+
+        let text = format!(r#"
+version = 1
+
+[[segment]]
+name = "upper"
+base = "refs/heads/feature"
+start = "{second}"
+head = "{second}"
+
+[[segment]]
+name = "feature"
+base = "refs/heads/main"
+start = "{first}"
+head = "{second}"
+
+[[sum]]
+name = "top"
+summands = ["refs/heads/feature", "refs/heads/side"]
+head = "{second}"
+"#);
+        let file = HierarchyFile::from_toml(&text).unwrap();
+        assert_eq!(HierarchyFile::from_toml(&file.to_toml()).unwrap(), file);
+
+        // upper waits for its base.
+        assert_eq!(apply(&repository, &file).unwrap(), vec![
+            ("feature".to_owned(), vec![Change::Created]),
+            ("top".to_owned(), vec![Change::Created]),
+            ("upper".to_owned(), vec![Change::Created]),
+        ]);
+        let nodes: Vec<_> = ["upper", "feature", "top"].iter().map(|n| load(&repository, n).unwrap()).collect();
+        assert_eq!(export(&repository, nodes.iter()).unwrap(), file);
+        assert_eq!(apply(&repository, &file).unwrap(), vec![
+            ("upper".to_owned(), vec![Change::Unchanged]),
+            ("feature".to_owned(), vec![Change::Unchanged]),
+            ("top".to_owned(), vec![Change::Unchanged]),
+        ]);
+
+        let mut changed = HierarchyFile::from_toml(&text).unwrap();
+        changed.segments[1].base = "refs/heads/side".to_owned();
+        changed.sums[0].summands = vec!["refs/heads/feature".to_owned(), "refs/heads/main".to_owned()];
+        assert_eq!(apply(&repository, &changed).unwrap()[1..], vec![
+            ("feature".to_owned(), vec![Change::Rebased {
+                from: "refs/heads/main".to_owned(),
+                to: "refs/heads/side".to_owned(),
+            }]),
+            ("top".to_owned(), vec![Change::SummandsAdded(vec!["refs/heads/main".to_owned()]),
+                                    Change::SummandsRemoved(vec!["refs/heads/side".to_owned()])]),
+        ]);
+
+        // nothing done.
+        changed.segments[1].base = "refs/heads/main".to_owned();
+        changed.sums[0].summands.push("refs/heads/missing".to_owned());
+        assert!(matches!(apply(&repository, &changed), Err(RebaseError::MissingReference { .. })));
+        assert_eq!(repository.find_reference("refs/base/feature").unwrap().symbolic_target(), Some("refs/heads/side"));
+    }
+}
@@ -1,12 +1,25 @@
 #![feature(try_trait_v2)]
 // #![feature(iterator_try_collect)]
 
+pub mod autostash;
 pub mod base;
+pub mod dependents;
+pub mod describe;
 pub mod execute;
+pub mod fetch;
 pub mod git_hierarchy;
 pub mod graph;
+pub mod hierarchy_file;
 pub mod permutation;
+pub mod push;
 pub mod utils;
 
 pub mod collected;
 pub mod rebase;
+pub mod rebase_state;
+pub mod rebase_worktree;
+pub mod share;
+pub mod snapshot;
+
+#[cfg(test)]
+mod testing;
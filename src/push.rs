@@ -0,0 +1,196 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// Push the segment & sum heads to their upstreams, with a lease: the remote
+// ref must still be where our remote-tracking ref says, i.e. nobody pushed
+// since we last fetched.  Checked during the push negotiation, like
+// git push --force-with-lease.
+
+use git2::{Oid, PushOptions, RemoteCallbacks, Repository};
+use serde::{Deserialize, Serialize};
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::fetch::{LOCAL_REMOTE, Upstream, branch_upstream};
+use crate::git_hierarchy::{GitHierarchy, optional_id};
+use crate::rebase::RebaseError;
+use crate::utils::{serde_oid, short};
+
+/// What we expect the upstream of the @branch to be, before pushing it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    pub branch: String,
+    /// None: not on the remote yet.
+    #[serde(default, with = "serde_oid::option")]
+    pub expected: Option<Oid>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PushStatus {
+    Pushed { from: Option<Oid>, to: Oid },
+    UpToDate,
+    /// no upstream, or a local one.
+    Skipped(String),
+    /// somebody pushed in between.
+    Stale { expected: Option<Oid>, found: Option<Oid> },
+    /// by the remote, e.g. a hook.
+    Rejected(String),
+}
+
+impl PushStatus {
+    pub fn failed(&self) -> bool {
+        matches!(self, PushStatus::Stale { .. } | PushStatus::Rejected(_))
+    }
+}
+
+fn short_or_none(oid: &Option<Oid>) -> String {
+    oid.map(short).unwrap_or("(none)".to_owned())
+}
+
+impl fmt::Display for PushStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushStatus::Pushed { from, to } => write!(f, "pushed {}..{}", short_or_none(from), short(*to)),
+            PushStatus::UpToDate => write!(f, "up-to-date"),
+            PushStatus::Skipped(reason) => write!(f, "skipped, {}", reason),
+            PushStatus::Stale { expected, found } =>
+                write!(f, "refused, the remote moved to {} (expected {}), fetch & rebase again",
+                       short_or_none(found), short_or_none(expected)),
+            PushStatus::Rejected(message) => write!(f, "rejected: {}", message),
+        }
+    }
+}
+
+/// The lease of the local @branch (full ref name), by its remote-tracking ref now.
+/// None if it has no upstream.
+pub fn lease_of(repository: &Repository, branch: &str) -> Result<Option<Lease>, RebaseError> {
+    let upstream = match branch_upstream(repository, branch) {
+        Ok(upstream) => upstream,
+        Err(RebaseError::NoUpstream { .. }) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let expected = optional_id(repository, &upstream.tracking)?;
+    Ok(Some(Lease { branch: branch.to_owned(), expected }))
+}
+
+/// The leases of all the segments & sums among the @nodes.
+pub fn hierarchy_leases<'repo, 'a>(repository: &'repo Repository,
+                                   nodes: impl Iterator<Item = &'a GitHierarchy<'repo>>,
+) -> Result<Vec<Lease>, RebaseError>
+where
+    'repo: 'a,
+{
+    let mut leases = Vec::new();
+    for node in nodes {
+        let name = match node {
+            GitHierarchy::Segment(segment) => segment.reference.borrow().name().unwrap().to_owned(),
+            GitHierarchy::Sum(sum) => sum.reference.borrow().name().unwrap().to_owned(),
+            _ => continue,
+        };
+        if let Some(lease) = lease_of(repository, &name)? {
+            leases.push(lease);
+        }
+    }
+    Ok(leases)
+}
+
+/// Force-push the @lease's branch to its upstream, unless the remote moved from the expected.
+pub fn push_with_lease(repository: &Repository, lease: &Lease) -> Result<PushStatus, RebaseError> {
+    let upstream: Upstream = match branch_upstream(repository, &lease.branch) {
+        Ok(upstream) => upstream,
+        Err(RebaseError::NoUpstream { .. }) => return Ok(PushStatus::Skipped("no upstream".to_owned())),
+        Err(e) => return Err(e),
+    };
+    if upstream.remote == LOCAL_REMOTE {
+        return Ok(PushStatus::Skipped(format!("local upstream {}", upstream.merge)));
+    }
+
+    let local = repository.refname_to_id(&lease.branch)?;
+    if Some(local) == lease.expected {
+        return Ok(PushStatus::UpToDate);
+    }
+
+    let stale = Cell::new(None);
+    let rejected = RefCell::new(None);
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.push_negotiation(|updates| {
+        for update in updates {
+            // zero when it does not exist there.
+            let found = Some(update.src()).filter(|oid| !oid.is_zero());
+            if update.dst_refname() == Some(upstream.merge.as_str()) && found != lease.expected {
+                stale.set(Some(found));
+                return Err(git2::Error::from_str("stale lease"));
+            }
+        }
+        Ok(())
+    });
+    callbacks.push_update_reference(|refname, status| {
+        if let Some(message) = status {
+            warn!("{} rejected: {}", refname, message);
+            *rejected.borrow_mut() = Some(message.to_owned());
+        }
+        Ok(())
+    });
+
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(callbacks);
+    let refspec = format!("+{}:{}", lease.branch, upstream.merge);
+    info!("push {} {}", upstream.remote, refspec);
+    let result = repository.find_remote(&upstream.remote)?.push(&[&refspec], Some(&mut push_options));
+
+    if let Some(found) = stale.get() {
+        return Ok(PushStatus::Stale { expected: lease.expected, found });
+    }
+    result?;
+    if let Some(message) = rejected.take() {
+        return Ok(PushStatus::Rejected(message));
+    }
+    Ok(PushStatus::Pushed { from: lease.expected, to: local })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{TempDir, empty_commit, init, init_bare};
+
+    #[test]
+    fn test_push_with_lease() {
+        let dir = TempDir::new("push");
+        let remote_repository = init_bare(&dir.join("remote"));
+        let repository = init(&dir.join("local"));
+
+        let first = empty_commit(&repository, "first", &[]);
+        let second = empty_commit(&repository, "second", &[first]);
+
+        repository.remote("origin", dir.join("remote").to_str().unwrap()).unwrap();
+        let mut config = repository.config().unwrap();
+        config.set_str("branch.work.remote", "origin").unwrap();
+        config.set_str("branch.work.merge", "refs/heads/team/work").unwrap();
+
+        // not on the remote yet.
+        repository.reference("refs/heads/work", first, true, "test").unwrap();
+        let lease = lease_of(&repository, "refs/heads/work").unwrap().unwrap();
+        assert_eq!(lease.expected, None);
+        assert_eq!(push_with_lease(&repository, &lease).unwrap(),
+                   PushStatus::Pushed { from: None, to: first });
+        assert_eq!(remote_repository.refname_to_id("refs/heads/team/work").unwrap(), first);
+
+        let lease = lease_of(&repository, "refs/heads/work").unwrap().unwrap();
+        assert_eq!(lease.expected, Some(first));
+        assert_eq!(push_with_lease(&repository, &lease).unwrap(), PushStatus::UpToDate);
+
+        // a teammate pushes.
+        repository.reference("refs/heads/teammate", second, true, "test").unwrap();
+        repository.find_remote("origin").unwrap()
+            .push(&["+refs/heads/teammate:refs/heads/team/work"], None).unwrap();
+        assert_eq!(remote_repository.refname_to_id("refs/heads/team/work").unwrap(), second);
+        repository.reference("refs/heads/work", second, true, "test").unwrap();
+        assert_eq!(push_with_lease(&repository, &lease).unwrap(),
+                   PushStatus::Stale { expected: Some(first), found: Some(second) });
+
+        assert_eq!(lease_of(&repository, "refs/heads/other").unwrap(), None);
+    }
+}
@@ -1,23 +1,21 @@
 #![deny(elided_lifetimes_in_paths)]
 
 // rebase segment.
-use git2::{Branch, BranchType, Error, Commit,
+use git2::{Branch, BranchType, Error, ErrorClass, ErrorCode, Commit,
            Oid,
            Repository,RepositoryState,
            StatusOptions, StatusShow,
 
-           CherrypickOptions,
+           CherrypickOptions, MergeOptions, ResetType,
            build::CheckoutBuilder,
 };
 
 use std::collections::HashMap;
-use std::fs::{self,OpenOptions};
-use std::io::{Write,self};
+use std::fs;
 use std::path::PathBuf;
-use std::process::exit; // fixme: drop in library
+use std::process::ExitStatus;
 #[allow(unused_imports)]
 use tracing::{span, Level, debug, info, warn,error};
-use colored::Colorize;
 
 use thiserror;
 
@@ -28,10 +26,16 @@ use crate::utils::{iterator_symmetric_difference};
 use crate::git_hierarchy::{GitHierarchy, Segment, Sum, load};
 use crate::graph::discover::NodeExpander;
 
-use crate::execute::git_run;
-use crate::base::{checkout_new_head_at,
-                  staged_files,
+use crate::autostash::unstash;
+use crate::rebase_worktree::remove_worktree;
+use crate::execute::{git_run, shell_run};
+use crate::rebase_state::{DroppedCommit, EmptyPolicy, RebaseState};
+use crate::base::{checkout_detached,
+                  checkout_new_head_at,
+                  conflicted_paths,
+                  detach_head_from,
                   is_linear_ancestor,
+                  repository_clean,
 };
 
 
@@ -48,72 +52,134 @@ pub enum RebaseError {
     WrongHierarchy(String),
     #[error("repository in wrong state during rebase")]
     WrongState,
-    #[error("rebase error")]
-    Default,
-    // Git2Error(#[from] git2::Error),
-}
-
-impl std::convert::From<crate::execute::Error> for RebaseError {
-    fn from(_e: crate::execute::Error) -> RebaseError{
-       RebaseError::Default
-    }
-}
-
-impl std::convert::From<git2::Error> for RebaseError {
-    fn from(_e: git2::Error) -> RebaseError{
-       RebaseError::Default
-    }
-}
-
-impl std::convert::From<std::io::Error> for RebaseError {
-    fn from(_e: std::io::Error) -> RebaseError{
-        // fixme!
-       RebaseError::Default
-    }
+    #[error("no rebase in progress")]
+    NotInProgress,
+    #[error("rebase state does not match the repository: {}", .0)]
+    StateMismatch(String),
+    #[error("re-merging {sum}: {summand} conflicts with {} in {}", .conflicts_with.join(", "), .paths.join(", "))]
+    SumConflict {
+        sum: String,
+        summand: String,
+        conflicts_with: Vec<String>,
+        paths: Vec<String>,
+    },
+    /// Stopped, the user should resolve & continue.
+    #[error("cherry-picking {commit} in segment {segment} conflicts in {}", .paths.join(", "))]
+    Conflict {
+        segment: String,
+        commit: Oid,
+        paths: Vec<String>,
+    },
+    #[error("cherry-picking {commit} in segment {segment} results in an empty commit")]
+    EmptyCommit {
+        segment: String,
+        commit: Oid,
+    },
+    #[error("cherry-picking {commit} in segment {segment} would overwrite local changes")]
+    DirtyWorktree {
+        segment: String,
+        commit: Oid,
+        #[source]
+        source: Option<git2::Error>,
+    },
+    /// --skip-commit, when the rebase did not stop at a commit, e.g. on local changes.
+    #[error("segment {segment} did not stop at a commit, nothing to skip")]
+    NothingToSkip {
+        segment: String,
+    },
+    #[error("cannot find {name}")]
+    MissingReference {
+        name: String,
+        #[source]
+        source: git2::Error,
+    },
+    /// fetching, with the `DivergedPolicy::FfOnly'.
+    #[error("{branch} has diverged from {upstream}: {ahead} local and {behind} upstream commits")]
+    Diverged {
+        branch: String,
+        upstream: String,
+        ahead: usize,
+        behind: usize,
+    },
+    #[error("{branch} has no upstream")]
+    NoUpstream {
+        branch: String,
+    },
+    #[error("cannot {action} {branch} onto {upstream}: conflicts in {}", .paths.join(", "))]
+    UpstreamConflict {
+        branch: String,
+        upstream: String,
+        action: &'static str,
+        paths: Vec<String>,
+    },
+    /// fetching, with the `DivergedPolicy::Rebase'.
+    #[error("cannot rebase {branch} onto {upstream}: {commit} is a merge")]
+    UpstreamMergeCommit {
+        branch: String,
+        upstream: String,
+        commit: Oid,
+    },
+    #[error("push refused for {}", .0.join(", "))]
+    PushRefused(Vec<String>),
+    #[error("local and remote definitions disagree for {}", .0.join(", "))]
+    DefinitionConflict(Vec<String>),
+    #[error("git {} failed", .0)]
+    GitCommand(String),
+    /// --jobs: a bug, the refs rebased so far stay, --abort restores them.
+    #[error("the worker rebasing {} panicked", .0)]
+    WorkerPanicked(String),
+    #[error("git config {key} = {value}: {reason}")]
+    InvalidConfig {
+        key: String,
+        value: String,
+        reason: String,
+    },
+    #[error("the ref name {} is not valid UTF-8", .0)]
+    InvalidRefName(String),
+    /// e.g. the hierarchy file.
+    #[error("invalid {}: {reason}", .path.display())]
+    InvalidFile {
+        path: PathBuf,
+        reason: String,
+    },
+    #[error("re-applying the autostash {stash} conflicts{}, it is kept in the stash list",
+            if .paths.is_empty() { String::new() } else { format!(" in {}", .paths.join(", ")) })]
+    AutostashConflict {
+        stash: Oid,
+        paths: Vec<String>,
+        #[source]
+        source: Option<git2::Error>,
+    },
+    /// --exec, the node is rebased but not done.
+    #[error("`{command}` failed on {node}: {status}")]
+    ExecFailed {
+        node: String,
+        command: String,
+        status: ExitStatus,
+    },
+    #[error("git operation failed: {}", .0.message())]
+    Git(#[from] git2::Error),
+    #[error("I/O error: {}", .0)]
+    Io(#[from] std::io::Error),
+    #[error("cannot run git")]
+    Execute(#[from] crate::execute::Error),
 }
 
 const TEMP_HEAD_NAME: &str = "tempSegment";
-const MARKER_FILENAME: &str = ".segment-cherry-pick";
 
-fn marker_filename(repository: &Repository) -> PathBuf {
-    repository.commondir().join(MARKER_FILENAME)
-}
 
-// see `cleanup_segment_rebase' which removes it.
-fn create_marker_file(repository: &Repository, content: &str) -> io::Result<()> {
-    let path = marker_filename(repository);
-    // todo: use a Git reference instead?
-    // persistent mark, if we fail, and during the session.
-    debug!("Create marker: {:?}", path);
-    fs::write(path, content)
+// per worktree, not in the common dir.
+fn read_cherry_pick_head(repository: &'_ Repository) -> std::io::Result<String> {
+    fs::read_to_string(repository.path().join("CHERRY_PICK_HEAD"))
 }
 
-/// Store persistently (between runs) the commit we stubled on
-/// see `segment_to_continue'() for the read part
-fn record_processed_commit(repository: &'_ Repository, oid: Oid, applied: bool) -> io::Result<()>{
-    let path = marker_filename(repository);
-    debug!("Update persistent state: {:?}", path);
-
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(path)
-        .unwrap();
-
-    let marker =
-        if applied {
-            "1"
-        } else {
-            "0"
-        };
-    writeln!(file, "{}", marker)?;
-    writeln!(file, "{}", oid)?;
-    debug!("{} {}", oid, marker);
-    Ok(())
+fn load_node<'repo>(repository: &'repo Repository, name: &str) -> Result<GitHierarchy<'repo>, RebaseError> {
+    load(repository, name).map_err(|source| RebaseError::MissingReference { name: name.to_owned(), source })
 }
 
-
-fn read_cherry_pick_head(repository: &'_ Repository) -> String {
-    fs::read_to_string(repository.commondir().join("CHERRY_PICK_HEAD")).unwrap()
+// for the errors.
+fn segment_in_progress(state: &RebaseState) -> String {
+    state.in_progress.as_ref().map(|p| p.node.clone()).unwrap_or_default()
 }
 
 /// Creates each commit during the rebase/cherry-picking: both in OK flow
@@ -122,54 +188,57 @@ fn read_cherry_pick_head(repository: &'_ Repository) -> String {
 ///
 fn commit_cherry_picked<'repo>(repository: &'repo Repository,
                                original: &Commit<'repo>,
-                               parent_commit: &Commit<'repo>) -> Oid {
-    let mut index = repository.index().unwrap();
+                               parent_commit: &Commit<'repo>,
+                               state: &mut RebaseState) -> Result<Oid, RebaseError> {
+    let mut index = repository.index()?;
     if index.has_conflicts() {
-        eprintln!("{}",Colorize::red("SORRY conflicts detected"));
-        eprintln!("{}",Colorize::red("resolve them, and either commit or stage them"));
-
+        warn!("conflicts detected in {}", original.id());
         // next time resume from this, `exclusive'.
-        record_processed_commit(repository, original.id(), true).unwrap();
-        exit(1);
+        state.record_commit(repository, original.id(), true)?;
+        return Err(RebaseError::Conflict {
+            segment: segment_in_progress(state),
+            commit: original.id(),
+            paths: conflicted_paths(&index)?,
+        });
     }
 
-    let statusses = staged_files(repository).unwrap();
-    if statusses.is_empty() {
-        eprintln!("SORRY nothing staged, empty -- skip?");
-        // so we have .git/CHERRY_PICK_HEAD ?
-        exit(1);
-    } else {
-        info!("something staged");
+    //  "cannot create a tree from a not fully merged index."
+    let tree_oid = index.write_tree()?;
+    if tree_oid == parent_commit.tree_id() {
+        match state.options.empty {
+            EmptyPolicy::Drop => {
+                state.drop_commit(repository, original)?;
+                repository.cleanup_state()?;
+                return Ok(parent_commit.id());
+            }
+            EmptyPolicy::Keep => {
+                info!("keeping the empty {}", original.id());
+            }
+            EmptyPolicy::Stop => {
+                // so we have .git/CHERRY_PICK_HEAD, continue goes on after it.
+                state.record_commit(repository, original.id(), true)?;
+                return Err(RebaseError::EmptyCommit {
+                    segment: segment_in_progress(state),
+                    commit: original.id(),
+                });
+            }
+        }
     }
 
-    let tree_oid = index.write_tree().unwrap();
-    let new_oid =
-        if repository.head().unwrap().peel_to_tree().unwrap().id() == tree_oid {
-            warn!("SORRY nothing staged, empty -- skip?");
-            // bug: and no changes in the worktree!
-            repository.head().unwrap().target().unwrap()
-            // silently skipping over?
-            // exit(1);
-        } else {
-            // same tree id ... it was empty!
-
-            //  "cannot create a tree from a not fully merged index."
-            let tree = repository.find_tree(tree_oid).unwrap();
-
-            repository.commit(
-                Some("HEAD"),
-                // copy over:
-                &original.author(),
-                &original.committer(),
-                original.message().unwrap(),
-                // and timestamps? part of those ^^ !
-                &tree,
-                &[parent_commit],
-            ).unwrap()
-        };
-
-    repository.cleanup_state().unwrap();
-    new_oid
+    let tree = repository.find_tree(tree_oid)?;
+    let new_oid = repository.commit(
+        Some("HEAD"),
+        // copy over:
+        &original.author(),
+        &original.committer(),
+        original.message().unwrap_or_default(),
+        // and timestamps? part of those ^^ !
+        &tree,
+        &[parent_commit],
+    )?;
+
+    repository.cleanup_state()?;
+    Ok(new_oid)
 }
 
 
@@ -177,101 +246,164 @@ fn commit_cherry_picked<'repo>(repository: &'repo Repository,
 // cherry-picks each commits from the iterator, and returns the HEAD afterwards/on error?
 fn cherry_pick_commits<'repo, T>(repository: &'repo Repository,
                                  iter: T,
-                                 base_commit: Commit<'repo>)
-                                 -> Result<Commit<'repo>, Error>
+                                 base_commit: Commit<'repo>,
+                                 state: &mut RebaseState)
+                                 -> Result<Commit<'repo>, RebaseError>
     where T: Iterator<Item = Result<Oid, Error> >
 {
-    let final_commit =
-        iter.fold(base_commit,
-                  |base_commit, oid_to_apply| {
-
-                      let to_apply = repository.find_commit(oid_to_apply.unwrap()).unwrap();
-
-                      // use `cherrypick'
-
-                      info!("cherry-pick commit: {:?}", to_apply);
-
-                      let mut checkout_opts = CheckoutBuilder::new();
-                      checkout_opts.safe();
-                      let mut cherrypick_opts = CherrypickOptions::new();
-                      cherrypick_opts.checkout_builder(checkout_opts);
-
-                      let result = repository.cherrypick(&to_apply, Some(&mut cherrypick_opts));
-
-                      if let Err(e) = result {
-                          eprintln!("cherrypick failed on {}\n {:?}",
-                                    to_apply.id(), e);
-                          eprintln!("error: code{:?}, class {:?}: {}",
-                                    e.code(),
-                                    e.class(),
-                                    e.message()
-                          );
-                          // code: -13, klass: 22, message: "1 uncommitted change would be overwritten by merge" }
-                          record_processed_commit(repository, to_apply.id(), true).unwrap();
-
-                          let index = repository.index().unwrap();
-                          if index.has_conflicts() {
-                              eprintln!("{}: SORRY conflicts detected", line!());
-                          }
+    let mut base_commit = base_commit;
+    for oid_to_apply in iter {
+        let to_apply = repository.find_commit(oid_to_apply?)?;
+
+        // use `cherrypick'
+        info!("cherry-pick commit: {:?}", to_apply);
+
+        let mut checkout_opts = CheckoutBuilder::new();
+        checkout_opts.safe();
+        let mut cherrypick_opts = CherrypickOptions::new();
+        cherrypick_opts.checkout_builder(checkout_opts);
+
+        if let Err(e) = repository.cherrypick(&to_apply, Some(&mut cherrypick_opts)) {
+            warn!("cherrypick failed on {}: code {:?}, class {:?}: {}",
+                  to_apply.id(), e.code(), e.class(), e.message());
+            // nothing applied, continue should retry it.
+            state.record_commit(repository, to_apply.id(), false)?;
+
+            // code: -13, klass: 22, message: "1 uncommitted change would be overwritten by merge" }
+            if e.code() == ErrorCode::Conflict && e.class() == ErrorClass::Checkout {
+                return Err(RebaseError::DirtyWorktree {
+                    segment: segment_in_progress(state),
+                    commit: to_apply.id(),
+                    source: Some(e),
+                });
+            }
+            return Err(e.into());
+        }
 
-                          eprintln!("should skip");
-                          exit(1);
-                      }
+        let new_oid = commit_cherry_picked(repository, &to_apply, &base_commit, state)?;
+        base_commit = repository.find_commit(new_oid)?;
+    }
 
-                      let new_oid = commit_cherry_picked(repository, &to_apply, &base_commit);
-                      let new_commit = repository.find_commit(new_oid).unwrap();
+    Ok(base_commit)
+}
 
-                      if false {
-                          info!("SLEEP");
-                          // sleep(Duration::from_secs(2));
-                      }
+/// Cherry-pick the @oids on top of @base_commit, without touching the worktree or the index.
+/// Stops before the first commit which conflicts, or would become empty and the policy is to stop.
+/// Returns how many were replayed, and the last commit created.  Empty commits
+/// of @segment left out are added to @dropped.
+fn cherry_pick_in_memory<'repo>(repository: &'repo Repository,
+                                oids: &[Oid],
+                                base_commit: Commit<'repo>,
+                                empty: EmptyPolicy,
+                                segment: &str,
+                                dropped: &mut Vec<DroppedCommit>)
+                                -> Result<(usize, Commit<'repo>), RebaseError> {
+    let mut parent = base_commit;
+
+    for (count, oid) in oids.iter().enumerate() {
+        let to_apply = repository.find_commit(*oid)?;
+        let mut index = repository.cherrypick_commit(&to_apply, &parent, 0, None)?;
+
+        if index.has_conflicts() {
+            info!("cherry-pick of {} conflicts", oid);
+            return Ok((count, parent));
+        }
 
-                      // return:
-                      new_commit});
+        let tree_oid = index.write_tree_to(repository)?;
+        if tree_oid == parent.tree_id() {
+            match empty {
+                EmptyPolicy::Drop => {
+                    info!("dropping empty {} from {}", oid, segment);
+                    dropped.push(DroppedCommit::new(segment, &to_apply));
+                    continue;
+                }
+                EmptyPolicy::Keep => {
+                    info!("keeping the empty {}", oid);
+                }
+                EmptyPolicy::Stop => {
+                    info!("cherry-pick of {} would be empty", oid);
+                    return Ok((count, parent));
+                }
+            }
+        }
 
-    Ok(final_commit)
+        let new_oid = repository.commit(
+            None,
+            &to_apply.author(),
+            &to_apply.committer(),
+            to_apply.message().unwrap_or_default(),
+            &repository.find_tree(tree_oid)?,
+            &[&parent],
+        )?;
+        debug!("cherry-picked {} as {}", oid, new_oid);
+        parent = repository.find_commit(new_oid)?;
+    }
+    Ok((oids.len(), parent))
 }
 
 /// Given a @segment, and HEAD ....
 /// either exit or rewrite the segment ....its reference should update oid.
-pub fn rebase_segment<'repo>(repository: &'repo Repository, segment: &Segment<'repo>) -> Result<RebaseResult, RebaseError> {
+pub fn rebase_segment<'repo>(repository: &'repo Repository,
+                             segment: &Segment<'repo>,
+                             state: &mut RebaseState) -> Result<RebaseResult, RebaseError> {
     if segment.uptodate(repository) {
         info!("nothing to do -- base and start equal");
         return Ok(RebaseResult::Nothing);
     }
 
-    let new_start = segment.base(repository).peel_to_commit().unwrap();
+    let new_start = segment.base(repository).peel_to_commit()?;
 
     if segment.empty(repository) {
         return rebase_empty_segment(segment, repository);
     }
 
+    info!("rebase_segment: {}", segment.name());
+    state.begin_node(repository, segment.name())?;
+
+    // we move the branch, the checkout must not follow it.
+    detach_head_from(repository, &segment.reference.borrow())?;
+
+    let oids = segment.iter(repository)?.collect::<Result<Vec<Oid>, Error>>()?;
+    let mut dropped = Vec::new();
+    let result = cherry_pick_in_memory(repository, &oids, new_start, state.options.empty,
+                                       segment.name(), &mut dropped);
+    state.add_dropped(repository, dropped)?;
+    let (replayed, parent) = result?;
+    if replayed == oids.len() {
+        segment.reset(repository, parent.id());
+        return Ok(RebaseResult::Done);
+    }
+
     // fixme: if we are in the middle of rebase?
     if repository.state() != RepositoryState::Clean {
         error!("the repository is not clean");
         return Err(RebaseError::WrongState);
     }
 
-    info!("rebase_segment: {}", segment.name());
+    info!("{} does not apply cleanly, continuing in the worktree", oids[replayed]);
+    if !repository_clean(repository) {
+        // continue starts the segment over.
+        return Err(RebaseError::DirtyWorktree {
+            segment: segment.name().to_owned(),
+            commit: oids[replayed],
+            source: None,
+        });
+    }
     debug!("rebasing by Cherry-picking {}!", segment.name());
 
-    // fixme: we should convert/wrap this io::error
-    // std::convert::From<std::io::Error>` is not implemented for `rebase::RebaseError`
-    create_marker_file(repository, &format!("{}\n", segment.name()))?;
-
     // checkout to that ref
     // todo: git stash
     // must change to the directory!
 
     let temp_head = TEMP_HEAD_NAME;
-    Branch::name_is_valid(temp_head).unwrap();
+    Branch::name_is_valid(temp_head)?;
 
-    checkout_new_head_at(repository, None, &new_start) ;
+    checkout_new_head_at(repository, None, &parent) ;
 
-    let sha = new_start.id();
+    let sha = parent.id();
     debug!("set-head: {:?}", &sha);
     // If I cherry-pick with temp as HEAD, it fails with ... "old reference value does not match"
-    repository.set_head_detached(sha).unwrap();
+    repository.set_head_detached(sha)?;
     /*
     repository.set_head_bytes(sha.as_bytes()).unwrap();
     */
@@ -286,43 +418,179 @@ pub fn rebase_segment<'repo>(repository: &'repo Repository, segment: &Segment<'r
             .is_ok_and(|x| x.success())
         {
             debug!("git cherry-pick failed");
-            return Err(RebaseError::Default)
+            return Err(RebaseError::GitCommand("cherry-pick".to_owned()))
         } else {
             return Ok(RebaseResult::Done);
         }
     } else {
         let commit = cherry_pick_commits(repository,
-                                         segment.iter(repository).unwrap(),
-                                         segment.base(repository).peel_to_commit().unwrap()
-                                         ).unwrap();
+                                         oids[replayed..].iter().map(|oid| Ok(*oid)),
+                                         parent,
+                                         state,
+                                         )?;
         // move
         segment.reset(repository, commit.id());
     }
 
-    cleanup_segment_rebase(repository, segment);
     Ok(RebaseResult::Done)
 }
 
+/// --jobs: rebase the @segment only if it can be done without the worktree, i.e. no
+/// conflicts, and no empty commits to stop at.  Nothing is recorded in the state,
+/// the caller adds the @dropped commits.
+/// Returns the new head, or None if the segment was left as it was.
+pub fn rebase_segment_in_memory<'repo>(repository: &'repo Repository,
+                                       segment: &Segment<'repo>,
+                                       empty: EmptyPolicy,
+                                       dropped: &mut Vec<DroppedCommit>,
+) -> Result<Option<Oid>, RebaseError> {
+    if segment.uptodate(repository) {
+        return Ok(Some(segment.reference.borrow().target().unwrap()));
+    }
+
+    let new_start = segment.base(repository).peel_to_commit()?;
+    let oids = segment.iter(repository)?.collect::<Result<Vec<Oid>, Error>>()?;
+    let mut picked = Vec::new();
+    let (replayed, parent) = cherry_pick_in_memory(repository, &oids, new_start, empty,
+                                                   segment.name(), &mut picked)?;
+    if replayed < oids.len() {
+        info!("{} cannot be rebased in memory, stopped at {}", segment.name(), oids[replayed]);
+        return Ok(None);
+    }
+
+    detach_head_from(repository, &segment.reference.borrow())?;
+    segment.reset(repository, parent.id());
+    dropped.append(&mut picked);
+    Ok(Some(parent.id()))
+}
+
+/// Merge the summand @commits natively, adding them one by one to the accumulated tree,
+/// like the octopus strategy does.  Nothing is checked out.
+/// Returns the tree of the merge, or which summands conflict with each other.
+pub fn merge_summands<'repo>(repository: &'repo Repository,
+                             sum_name: &str,
+                             commits: &[Commit<'repo>],
+                             names: &[&str],
+) -> Result<Oid, RebaseError> {
+    let mut merge_opts = MergeOptions::new();
+    merge_opts.standard_style(true)
+        .ignore_whitespace_change(true)
+        .patience(true)
+        .minimal(true);
+
+    let mut merged_tree = commits[0].tree()?;
+    let mut merged = vec![commits[0].id()];
+
+    for (i, commit) in commits.iter().enumerate().skip(1) {
+        merged.push(commit.id());
+        let ancestor = repository.find_commit(repository.merge_base_many(&merged)?)?;
+        debug!("merging {} with base {}", names[i], ancestor.id());
+
+        let mut index = repository.merge_trees(&ancestor.tree()?, &merged_tree, &commit.tree()?,
+                                               Some(&merge_opts))?;
+        if index.has_conflicts() {
+            // which of the previous ones is it?
+            let mut conflicts_with = Vec::new();
+            for (j, previous) in commits[..i].iter().enumerate() {
+                if repository.merge_commits(previous, commit, Some(&merge_opts))?.has_conflicts() {
+                    conflicts_with.push(names[j].to_owned());
+                }
+            }
+            if conflicts_with.is_empty() {
+                // only the combination of them.
+                conflicts_with.extend(names[..i].iter().map(|name| name.to_string()));
+            }
+            return Err(RebaseError::SumConflict {
+                sum: sum_name.to_owned(),
+                summand: names[i].to_owned(),
+                conflicts_with,
+                paths: conflicted_paths(&index)?,
+            });
+        }
+        merged_tree = repository.find_tree(index.write_tree_to(repository)?)?;
+    }
+    Ok(merged_tree.id())
+}
+
+/// Compose commit message for the Sum/Merge of .... components given by the
+/// first/others.
+pub fn get_merge_commit_message<'a, 'b, 'c, Iter>(
+    sum_name: &'b str,
+    first: &'c str,
+    others: Iter,
+) -> String
+where
+    Iter: Iterator<Item = &'a str>,
+{
+    let mut message = format!("Sum: {sum_name}\n\n{}", first);
+
+    const NAMES_PER_LINE: usize = 3;
+    for (i, name) in others.enumerate() {
+        message.push_str(" + ");
+        message.push_str(name);
+
+        if i % NAMES_PER_LINE == 0 {
+            // exactly same as push_str()
+            message += "\n"
+        }
+    }
+    message
+}
+
+/// Create the merge commit of the summand @commits (they become the parents in this order)
+/// and move the @sum to it.
+pub fn commit_sum<'repo>(repository: &'repo Repository,
+                         sum: &Sum<'repo>,
+                         commits: &[Commit<'repo>],
+                         names: &[&str],
+) -> Result<Oid, RebaseError> {
+    let message = get_merge_commit_message(
+        sum.name(),
+        names[0],
+        names.iter().skip(1).copied(),
+    );
+
+    let tree_oid = merge_summands(repository, sum.name(), commits, names)?;
+    let tree = repository.find_tree(tree_oid)?;
+
+    let sig = repository.signature()?;
+    // references
+    let commits_refs = commits.iter().collect::<Vec<_>>();
+
+    let new_oid = repository.commit(
+        None,
+        &sig, // author(),
+        &sig, // committer(),
+        &message,
+        &tree,
+        &commits_refs,
+    )?;
+
+    // we move the branch, the checkout must not follow it.
+    detach_head_from(repository, &sum.reference.borrow())?;
+    // this both on the Repo/storer both here in our Data ?
+    sum.reset(new_oid);
+    Ok(new_oid)
+}
+
 // The old, using git(1)
 #[allow(unused)]
 fn rebase_continue_git1(repository: &Repository, segment_name: &str) -> Result<RebaseResult, RebaseError> {
     if !git_run(repository, &["cherry-pick", "--continue"]).is_ok_and(|x| x.success()) {
         info!("git cherry-pick --continue failed");
-        return Err(RebaseError::Default);
+        return Err(RebaseError::GitCommand("cherry-pick --continue".to_owned()));
     }
 
-    if let GitHierarchy::Segment(segment) = load(repository, segment_name)? {
+    if let GitHierarchy::Segment(segment) = load_node(repository, segment_name)? {
         let tmp_head: Branch<'_> = repository
             .find_branch(TEMP_HEAD_NAME, BranchType::Local)
-            .unwrap();
+            .map_err(|source| RebaseError::MissingReference { name: TEMP_HEAD_NAME.to_owned(), source })?;
         if tmp_head.is_head() {
             //name: &str, branch_type: BranchType) -> Result<Branch<'_>, Error> {head();
-            panic!("rebase_segment_finish not supported anymore");
-            cleanup_segment_rebase(repository, &segment);
-            Ok(RebaseResult::Done)
+            panic!("rebase_segment_finish not supported anymore: {}", segment.name());
         } else {
             // mismatch
-            Err(RebaseError::Default)
+            Err(RebaseError::WrongState)
         }
     } else {
         Ok(RebaseResult::Nothing)
@@ -335,11 +603,12 @@ fn rebase_continue_git1(repository: &Repository, segment_name: &str) -> Result<R
 fn continue_segment_cherry_pick<'repo>(repository: &'repo Repository,
                                        segment: &'_ Segment<'repo>,
                                        commit_id: Oid,
-                                       skip: usize
+                                       skip: usize,
+                                       state: &mut RebaseState,
 ) -> Result<(), RebaseError> {
     // Find & skip:
-    let iter = segment.iter(repository).unwrap()
-        .skip_while(|x| x.as_ref().unwrap() != &commit_id );
+    let iter = segment.iter(repository)?
+        .skip_while(|x| x.as_ref().is_ok_and(|oid| oid != &commit_id));
 
     let mut peek = iter.peekable();
     if peek.peek().is_none() {
@@ -355,64 +624,43 @@ fn continue_segment_cherry_pick<'repo>(repository: &'repo Repository,
 
     // check we are in a clean state!
     // The default, if unspecified, is to show the index and the working
-    let statuses = repository.statuses(None)?;
-    if ! statuses.len() == 0 {
-        eprintln!("Status is not clean!");
-        return Err(RebaseError::WrongState);
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let statuses = repository.statuses(Some(&mut options))?;
+    if !statuses.is_empty() {
+        debug!("{} changed files", statuses.len());
+        return Err(RebaseError::DirtyWorktree {
+            segment: segment.name().to_owned(),
+            commit: commit_id,
+            source: None,
+        });
     }
 
     let commit = cherry_pick_commits(repository,
                                      peek.skip(skip),
-                                     parent).unwrap();
+                                     parent,
+                                     state)?;
     // might need this if nothing to cherrypick anymore.
     segment.reset(repository, commit.id());
     Ok(())
 }
 
 
-/// loads the persistent state: the commit we last processed
-// This should be <>
-pub fn segment_to_continue(repository: &Repository) -> Option<(String,Option<(String,usize)>)>
-{
-    let path = marker_filename(repository);
-
-    if ! fs::exists(&path).unwrap() {
-        // eprintln!
-        warn!("marker file does not exist -- no segment is being rebased.");
-        return None;
-    }
-
-    let content: String = fs::read_to_string(path).unwrap(); // .... and the oid
-    let mut lines = content.lines();
-    let segment_name = lines.next().unwrap().trim().to_owned();
-
-    // this can fail: if we failed on the last commit, at the moment of commit -- empty or whatever.
-    match lines.next_back() {
-        None =>
-            return Some((segment_name, None)),
-        Some(oid) => {
-            let skip : usize = lines.next_back().unwrap().parse().unwrap();
-            debug!("from file: continue on {}, after {:?}", segment_name, oid);
-            Some((segment_name, Some((oid.to_owned(), skip))))
-        }
-    }
-}
-
 // Continue after an issue:
 // either cherry-pick conflicts resolved by the user, or
 // he left mess, and ....on detached head. Unlike other tools.
-pub fn rebase_segment_continue(repository: &Repository) -> Result<RebaseResult, RebaseError> {
-    // todo: this might be the input:
-    let (segment_name, rest) = segment_to_continue(repository).unwrap();
-    let mut skip=0;
+pub fn rebase_segment_continue(repository: &Repository, state: &mut RebaseState) -> Result<RebaseResult, RebaseError> {
+    let in_progress = state.in_progress.clone().ok_or(RebaseError::NotInProgress)?;
+    let segment_name = in_progress.node;
+    let skip;
 
-    if let GitHierarchy::Segment(segment) = load(repository, &segment_name).unwrap() {
+    if let GitHierarchy::Segment(segment) = load_node(repository, &segment_name)? {
         let commit_id =
             if repository.state() == RepositoryState::CherryPick {
                 // read the CHERRY_PICK_HEAD
                 // todo: convert to step.step2...
                 // mmc: so this is the same as `oid' ?
-                let commit_id = Oid::from_str(read_cherry_pick_head(repository).as_str().trim()).unwrap();
+                let commit_id = Oid::from_str(read_cherry_pick_head(repository)?.trim())?;
                 debug!("should continue the cherry-pick {:?}", commit_id);
 
                 let mut option =  StatusOptions::new();
@@ -425,20 +673,27 @@ pub fn rebase_segment_continue(repository: &Repository) -> Result<RebaseResult,
 
                     // commit it, or reset the state?
                     debug!("non-empty index -> commit...");
-                    let to_apply = repository.find_commit(commit_id).unwrap();
+                    let to_apply = repository.find_commit(commit_id)?;
 
-                    let parent = repository.head().unwrap().peel_to_commit().unwrap();
+                    let parent = repository.head()?.peel_to_commit()?;
                     let new_oid = commit_cherry_picked(repository,
                                                        // todo: it's okay to skip:
                                                        &to_apply,
-                                                       &parent);
+                                                       &parent,
+                                                       state)?;
                     debug!("new commit created {new_oid}");
                     // parent = repository.find_commit(new_oid).unwrap();
+                } else if state.options.empty == EmptyPolicy::Keep {
+                    debug!("empty index -> commit anyway");
+                    let to_apply = repository.find_commit(commit_id)?;
+                    let parent = repository.head()?.peel_to_commit()?;
+                    commit_cherry_picked(repository, &to_apply, &parent, state)?;
                 } else {
                     // the user might have decided to drop this change -- skip over.
                     // todo: reset
                     info!("Cleaning cherry pick info: user unstaged the change");
-                    repository.cleanup_state().unwrap();
+                    state.drop_commit(repository, &repository.find_commit(commit_id)?)?;
+                    repository.cleanup_state()?;
                 }
                 skip = 1;
                 // we need the next one.
@@ -446,36 +701,117 @@ pub fn rebase_segment_continue(repository: &Repository) -> Result<RebaseResult,
             } else {
                 debug!("so cherry-pick finished, for some reason we need to continue");
                 // this means .... we couldn't start cherry-pick?
-                if let Some((oid, stored_skip)) = rest {
-                    skip = stored_skip;
-                    Oid::from_str(&oid).unwrap()
+                if let Some(oid) = in_progress.commit {
+                    skip = in_progress.skip;
+                    oid
                 } else {
-                    panic!("don't know which commit to continue from")
+                    // stopped before touching the worktree, e.g. it was dirty.
+                    info!("restarting segment {}", segment_name);
+                    rebase_segment(repository, &segment, state)?;
+                    state.end_node(repository)?;
+                    return Ok(RebaseResult::Done);
                 }
             };
 
-        eprintln!("should cherry-pick starting from oid {} + {}", commit_id, skip);
-        // so we should save it now!
-        record_processed_commit(repository, commit_id, true).unwrap();
+        finish_segment(repository, &segment, commit_id, skip, state)
+    } else {
+        Err(RebaseError::WrongHierarchy(segment_name))
+    }
+}
+
+// cherry-pick the rest of the @segment, after/from @commit_id, and mark it done.
+fn finish_segment<'repo>(repository: &'repo Repository,
+                         segment: &Segment<'repo>,
+                         commit_id: Oid,
+                         skip: usize,
+                         state: &mut RebaseState,
+) -> Result<RebaseResult, RebaseError> {
+    info!("should cherry-pick starting from oid {} + {}", commit_id, skip);
+    // so we should save it now!
+    state.record_commit(repository, commit_id, true)?;
 
-        // assert!(repository_clean(repository));
-        continue_segment_cherry_pick(repository, &segment, commit_id, skip)?; // starting from where?
+    // assert!(repository_clean(repository));
+    continue_segment_cherry_pick(repository, segment, commit_id, skip, state)?; // starting from where?
 
-        segment.reset(repository,
-                      repository.head().unwrap().peel_to_commit().unwrap().id());
+    let head = repository.head()?.peel_to_commit()?.id();
+    segment.reset(repository, head);
 
-        cleanup_segment_rebase(repository, &segment);
-        Ok(RebaseResult::Done)
-    } else {
-        Err(RebaseError::WrongHierarchy(segment_name))
+    // `rebase_tree' marks it done.
+    state.end_node(repository)?;
+    Ok(RebaseResult::Done)
+}
+
+/// Like `git rebase --skip': throw away the commit we stopped at, and continue the segment.
+pub fn rebase_segment_skip(repository: &Repository, state: &mut RebaseState) -> Result<RebaseResult, RebaseError> {
+    let in_progress = state.in_progress.clone().ok_or(RebaseError::NotInProgress)?;
+    let segment_name = in_progress.node;
+
+    let GitHierarchy::Segment(segment) = load_node(repository, &segment_name)? else {
+        return Err(RebaseError::WrongHierarchy(segment_name));
+    };
+
+    let commit_id =
+        if repository.state() == RepositoryState::CherryPick {
+            let commit_id = Oid::from_str(read_cherry_pick_head(repository)?.trim())?;
+            // drop whatever of it is in the index & worktree.
+            let head = repository.head()?.peel_to_commit()?;
+            repository.reset(head.as_object(), ResetType::Hard, None)?;
+            repository.cleanup_state()?;
+            commit_id
+        } else {
+            // stopped before applying it, nothing to clean.
+            in_progress.commit.ok_or_else(|| RebaseError::NothingToSkip { segment: segment_name.clone() })?
+        };
+
+    state.drop_commit(repository, &repository.find_commit(commit_id)?)?;
+    finish_segment(repository, &segment, commit_id, 1, state)
+}
+
+/// Checkout the (rebased) @node, and run the shell @command on it.
+/// The node is passed in GIT_HIERARCHY_NODE, GIT_HIERARCHY_KIND and GIT_HIERARCHY_HEAD.
+pub fn exec_node(repository: &Repository, node: &GitHierarchy<'_>, command: &str) -> Result<(), RebaseError> {
+    let (name, kind) = match node {
+        GitHierarchy::Segment(segment) => (segment.name(), "segment"),
+        GitHierarchy::Sum(sum) => (sum.name(), "sum"),
+        _ => return Ok(()),
+    };
+    let commit = node.commit()?;
+    checkout_detached(repository, &commit)?;
+
+    info!("exec on {}: {}", name, command);
+    let status = shell_run(repository, command, &[
+        ("GIT_HIERARCHY_NODE", name),
+        ("GIT_HIERARCHY_KIND", kind),
+        ("GIT_HIERARCHY_HEAD", &commit.id().to_string()),
+    ])?;
+    if !status.success() {
+        return Err(RebaseError::ExecFailed {
+            node: name.to_owned(),
+            command: command.to_owned(),
+            status,
+        });
     }
+    Ok(())
 }
 
-// bad name:
-fn cleanup_segment_rebase(repository: &Repository, _segment: &Segment<'_>) {
-    let path = marker_filename(repository);
-    debug!("delete marker: {:?}", path);
-    fs::remove_file(path).unwrap();
+/// Give up the whole poset rebase: put back all the refs recorded before it started,
+/// drop the cherry-pick state and checkout what was checked out.
+pub fn rebase_abort(repository: &Repository) -> Result<RebaseResult, RebaseError> {
+    let state = RebaseState::load(repository)?.ok_or(RebaseError::NotInProgress)?;
+
+    if let Some(path) = &state.worktree {
+        // the user's checkout was not touched.
+        state.original.restore_refs(repository)?;
+        remove_worktree(path)?;
+    } else {
+        repository.cleanup_state()?;
+        state.original.restore(repository)?;
+    }
+    RebaseState::remove(repository)?;
+    if let Some(stash) = state.autostash {
+        unstash(repository, stash)?;
+    }
+    Ok(RebaseResult::Done)
 }
 
 fn rebase_empty_segment<'repo>(
@@ -513,20 +849,13 @@ pub fn check_segment(repository: &Repository, segment: &Segment<'_>) -> Result<(
     Ok(())
 }
 
-pub fn check_sum<'repo>(
+/// Compare the summands of the @sum with the parents of its merge commit:
+/// returns the summand commits which are not parents, and the parents which are not summands.
+pub fn sum_difference<'repo>(
     repository: &'repo Repository,
     sum: &Sum<'repo>,
     object_map: &HashMap<String, GitHierarchy<'repo>>,
-) -> Result<(), RebaseError> {
-
-    // terrible:
-    // !i>2 in Rust  means ~i>2 in C
-    // https://users.rust-lang.org/t/why-does-rust-use-the-same-symbol-for-bitwise-not-or-inverse-and-logical-negation/117337/2
-    if let count = sum.summand_count() && count <= 1 {
-        warn!("not a merge: {}, only {} parent commits", sum.name(), count);
-        return Err(RebaseError::WrongHierarchy(sum.name().to_owned()));
-    };
-
+) -> (Vec<Oid>, Vec<Oid>) {
     // each of the summands has relationship to a parent commit.
     let summands = sum.summands(repository);
     /* assumption:
@@ -561,14 +890,30 @@ pub fn check_sum<'repo>(
         debug!("  {}", c);
     }
 
-    let (u,v) = iterator_symmetric_difference(
+    iterator_symmetric_difference(
         graphed_summands.iter().map(|gh| {
             debug!("mapping {:?} to {:?}", gh.node_identity(),
                    gh.commit().unwrap().id());
             gh.commit().unwrap().id()
         }),
-        parent_commits);
+        parent_commits)
+}
+
+pub fn check_sum<'repo>(
+    repository: &'repo Repository,
+    sum: &Sum<'repo>,
+    object_map: &HashMap<String, GitHierarchy<'repo>>,
+) -> Result<(), RebaseError> {
+
+    // terrible:
+    // !i>2 in Rust  means ~i>2 in C
+    // https://users.rust-lang.org/t/why-does-rust-use-the-same-symbol-for-bitwise-not-or-inverse-and-logical-negation/117337/2
+    if let count = sum.summand_count() && count <= 1 {
+        warn!("not a merge: {}, only {} parent commits", sum.name(), count);
+        return Err(RebaseError::WrongHierarchy(sum.name().to_owned()));
+    };
 
+    let (u,v) = sum_difference(repository, sum, object_map);
 
     if !(u.is_empty() && v.is_empty()) {
         warn!("sum {} is not well-positioned", sum.name());
@@ -0,0 +1,352 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// Persistent state of a poset rebase, between the runs: `git-rebase-poset --continue'
+// resumes the whole hierarchy from it, `--abort' restores the `original' refs.
+
+use git2::{Commit, ErrorCode, Oid, Repository};
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::fetch::DivergedPolicy;
+use crate::git_hierarchy::{GitHierarchy, load};
+use crate::push::Lease;
+use crate::rebase::RebaseError;
+use crate::snapshot::RefSnapshot;
+use crate::utils::{extract_name, serde_oid};
+
+const STATE_FILENAME: &str = ".poset-rebase-state";
+pub const STATE_VERSION: u32 = 1;
+
+const EMPTY_POLICY_CONFIG: &str = "hierarchy.empty";
+
+/// What to do with a commit which becomes empty when cherry-picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyPolicy {
+    /// skip it, and report it at the end.
+    Drop,
+    /// commit it anyway.
+    Keep,
+    /// let the user decide, then --continue.
+    #[default]
+    Stop,
+}
+
+impl FromStr for EmptyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(EmptyPolicy::Drop),
+            "keep" => Ok(EmptyPolicy::Keep),
+            "stop" => Ok(EmptyPolicy::Stop),
+            _ => Err(format!("invalid empty policy {}, expected drop, keep or stop", s)),
+        }
+    }
+}
+
+impl EmptyPolicy {
+    /// The `hierarchy.empty' git config, if set.
+    pub fn from_config(repository: &Repository) -> Result<Option<EmptyPolicy>, RebaseError> {
+        match repository.config()?.get_string(EMPTY_POLICY_CONFIG) {
+            Ok(value) => value.parse().map(Some)
+                .map_err(|reason| RebaseError::InvalidConfig { key: EMPTY_POLICY_CONFIG.to_owned(), value, reason }),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The command line options of the first run, re-used by `--continue'.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RebaseOptions {
+    pub fetch: bool,
+    pub ignore: Vec<String>,
+    pub skip: Vec<String>,
+    #[serde(default)]
+    pub empty: EmptyPolicy,
+    /// shell command run on each rebased segment & sum.
+    #[serde(default)]
+    pub exec: Option<String>,
+    #[serde(default)]
+    pub autostash: bool,
+    /// stash the untracked files too.
+    #[serde(default)]
+    pub autostash_untracked: bool,
+    /// work in a private linked worktree.
+    #[serde(default)]
+    pub worktree: bool,
+    /// rebase up to this many independent nodes at once.
+    #[serde(default)]
+    pub jobs: usize,
+    /// for the fetched plain branches, overrides their git config.
+    #[serde(default)]
+    pub diverged: Option<DivergedPolicy>,
+    /// push the rebased segments & sums at the end.
+    #[serde(default)]
+    pub push: bool,
+}
+
+/// A node already rebased, and where it was moved to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DoneNode {
+    pub name: String,
+    #[serde(with = "serde_oid")]
+    pub oid: Oid,
+}
+
+/// A commit left out of its segment: it became empty, or was dropped with --skip-commit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DroppedCommit {
+    pub segment: String,
+    #[serde(with = "serde_oid")]
+    pub oid: Oid,
+    pub summary: String,
+}
+
+impl DroppedCommit {
+    pub fn new(segment: &str, commit: &Commit<'_>) -> DroppedCommit {
+        DroppedCommit {
+            segment: segment.to_owned(),
+            oid: commit.id(),
+            summary: commit.summary().unwrap_or_default().to_owned(),
+        }
+    }
+}
+
+/// The segment being cherry-picked, and the commit we stopped at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InProgress {
+    pub node: String,
+    #[serde(default, with = "serde_oid::option")]
+    pub commit: Option<Oid>,
+    /// 1 if the `commit' has been dealt with already, and continue should start after it.
+    pub skip: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RebaseState {
+    pub version: u32,
+    pub root: String,
+    pub discovery_order: Vec<String>,
+    pub done: Vec<DoneNode>,
+    pub in_progress: Option<InProgress>,
+    pub options: RebaseOptions,
+    pub original: RefSnapshot,
+    #[serde(default)]
+    pub dropped: Vec<DroppedCommit>,
+    /// the local changes, to re-apply at the end.
+    #[serde(default, with = "serde_oid::option")]
+    pub autostash: Option<Oid>,
+    /// the linked worktree the rebase runs in.
+    #[serde(default)]
+    pub worktree: Option<PathBuf>,
+    /// --push: the upstreams as seen before the rebase.
+    #[serde(default)]
+    pub leases: Vec<Lease>,
+}
+
+fn state_filename(repository: &Repository) -> PathBuf {
+    repository.commondir().join(STATE_FILENAME)
+}
+
+// node names are either short or full ref names.
+fn same_node(a: &str, b: &str) -> bool {
+    extract_name(a) == extract_name(b)
+}
+
+impl RebaseState {
+    pub fn new(
+        root: String,
+        discovery_order: Vec<String>,
+        options: RebaseOptions,
+        original: RefSnapshot,
+    ) -> RebaseState {
+        RebaseState {
+            version: STATE_VERSION,
+            root,
+            discovery_order,
+            done: Vec::new(),
+            in_progress: None,
+            options,
+            original,
+            dropped: Vec::new(),
+            autostash: None,
+            worktree: None,
+            leases: Vec::new(),
+        }
+    }
+
+    pub fn exists(repository: &Repository) -> bool {
+        state_filename(repository).exists()
+    }
+
+    pub fn load(repository: &Repository) -> Result<Option<RebaseState>, RebaseError> {
+        let path = state_filename(repository);
+        if !path.exists() {
+            return Ok(None);
+        }
+        debug!("loading state from {:?}", path);
+        Self::parse(&fs::read_to_string(path)?).map(Some)
+    }
+
+    fn parse(content: &str) -> Result<RebaseState, RebaseError> {
+        let state: RebaseState = serde_json::from_str(content)
+            .map_err(|e| RebaseError::StateMismatch(format!("cannot parse the state file: {}", e)))?;
+
+        if state.version != STATE_VERSION {
+            return Err(RebaseError::StateMismatch(
+                format!("state file version {}, expected {}", state.version, STATE_VERSION)));
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, repository: &Repository) -> Result<(), RebaseError> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| RebaseError::StateMismatch(e.to_string()))?;
+        fs::write(state_filename(repository), content)?;
+        Ok(())
+    }
+
+    pub fn remove(repository: &Repository) -> io::Result<()> {
+        let path = state_filename(repository);
+        if path.exists() {
+            debug!("delete state: {:?}", path);
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_done(&self, name: &str) -> bool {
+        self.done.iter().any(|d| same_node(&d.name, name))
+    }
+
+    /// We start cherry-picking the segment @name.
+    pub fn begin_node(&mut self, repository: &Repository, name: &str) -> Result<(), RebaseError> {
+        self.in_progress = Some(InProgress { node: name.to_owned(), commit: None, skip: 0 });
+        self.save(repository)
+    }
+
+    /// Store the commit we stumbled on. See `rebase_segment_continue'.
+    pub fn record_commit(&mut self, repository: &Repository, oid: Oid, applied: bool)
+                         -> Result<(), RebaseError> {
+        let in_progress = self.in_progress.as_mut().ok_or(RebaseError::NotInProgress)?;
+        in_progress.commit = Some(oid);
+        in_progress.skip = applied as usize;
+        debug!("{} {}", oid, in_progress.skip);
+        self.save(repository)
+    }
+
+    /// @commit, of the segment in progress, is left out.
+    pub fn drop_commit(&mut self, repository: &Repository, commit: &Commit<'_>) -> Result<(), RebaseError> {
+        let segment = &self.in_progress.as_ref().ok_or(RebaseError::NotInProgress)?.node;
+        info!("dropping empty {} from {}", commit.id(), segment);
+        self.dropped.push(DroppedCommit::new(segment, commit));
+        self.save(repository)
+    }
+
+    pub fn add_dropped(&mut self, repository: &Repository, mut dropped: Vec<DroppedCommit>)
+                       -> Result<(), RebaseError> {
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        self.dropped.append(&mut dropped);
+        self.save(repository)
+    }
+
+    /// The segment is rebased, but not done yet: the --exec command can still fail.
+    pub fn end_node(&mut self, repository: &Repository) -> Result<(), RebaseError> {
+        self.in_progress = None;
+        self.save(repository)
+    }
+
+    pub fn node_done(&mut self, repository: &Repository, name: &str, oid: Oid) -> Result<(), RebaseError> {
+        info!("done: {} at {}", name, oid);
+        if self.in_progress.as_ref().is_some_and(|p| same_node(&p.node, name)) {
+            self.in_progress = None;
+        }
+        self.done.push(DoneNode { name: name.to_owned(), oid });
+        self.save(repository)
+    }
+
+    /// Check that the refs are still where this state left them.
+    pub fn validate(&self, repository: &Repository) -> Result<(), RebaseError> {
+        let mismatch = |what: String| Err(RebaseError::StateMismatch(what));
+
+        for name in &self.discovery_order {
+            if let Err(e) = load(repository, name) {
+                return mismatch(format!("{} cannot be loaded: {}", name, e.message()));
+            }
+        }
+
+        for done in &self.done {
+            let gh = load(repository, &done.name)?;
+            let oid = gh.commit()?.id();
+            if oid != done.oid {
+                return mismatch(format!("{} moved to {} after being rebased to {}", done.name, oid, done.oid));
+            }
+        }
+
+        if let Some(in_progress) = &self.in_progress {
+            match load(repository, &in_progress.node)? {
+                GitHierarchy::Segment(segment) => {
+                    if let Some(commit) = in_progress.commit
+                        && !segment.iter(repository)?.any(|oid| oid.is_ok_and(|oid| oid == commit)) {
+                            return mismatch(format!("commit {} is not in segment {}", commit, segment.name()));
+                        }
+                }
+                _ => {
+                    return mismatch(format!("{} is not a segment", in_progress.node));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::snapshot::{OriginalHead, RecordedRef};
+
+    #[test]
+    fn test_empty_policy() {
+        assert_eq!("drop".parse(), Ok(EmptyPolicy::Drop));
+        assert_eq!("keep".parse(), Ok(EmptyPolicy::Keep));
+        assert!("skip".parse::<EmptyPolicy>().is_err());
+        assert_eq!(serde_json::to_string(&EmptyPolicy::Stop).unwrap(), "\"stop\"");
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let mut state = RebaseState::new(
+            "top".to_owned(),
+            vec!["refs/heads/master".to_owned(), "refs/heads/a".to_owned(), "top".to_owned()],
+            RebaseOptions { fetch: true, ..Default::default() },
+            RefSnapshot {
+                head: OriginalHead::Branch("refs/heads/top".to_owned()),
+                refs: vec![RecordedRef { name: "refs/heads/a".to_owned(), oid }],
+            });
+        state.done.push(DoneNode { name: "refs/heads/master".to_owned(), oid });
+        state.in_progress = Some(InProgress { node: "a".to_owned(), commit: Some(oid), skip: 1 });
+
+        let parsed = RebaseState::parse(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(parsed.discovery_order, state.discovery_order);
+        assert!(parsed.is_done("master"));
+        assert!(!parsed.is_done("a"));
+        assert_eq!(parsed.in_progress.unwrap().commit, Some(oid));
+        assert_eq!(parsed.original.refs, state.original.refs);
+        assert_eq!(parsed.options.empty, EmptyPolicy::Stop);
+
+        state.version = STATE_VERSION + 1;
+        assert!(RebaseState::parse(&serde_json::to_string(&state).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// --worktree: git-rebase-poset works in a private linked worktree, so the
+// user's checkout stays as it is.  The worktree lives under the common dir,
+// next to the `RebaseState', which remembers its path.
+
+use git2::{Repository, Worktree, WorktreePruneOptions};
+
+use std::path::{Path, PathBuf};
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::rebase::RebaseError;
+
+const WORKTREE_PREFIX: &str = "poset-rebase-";
+
+// per run: no branch, worktree nor directory of that name exists.  So the branch
+// `Repository::worktree' creates is ours, and a leftover of a killed run is not reused.
+fn free_worktree_name(repository: &Repository) -> Result<(String, PathBuf), RebaseError> {
+    let worktrees = repository.worktrees()?;
+    for n in 0.. {
+        let name = format!("{}{}-{}", WORKTREE_PREFIX, std::process::id(), n);
+        let path = repository.commondir().join(format!("{}-worktree", name));
+        if repository.find_branch(&name, git2::BranchType::Local).is_err()
+            && !worktrees.iter().any(|w| w == Some(name.as_str()))
+            && !path.exists()
+        {
+            return Ok((name, path));
+        }
+    }
+    unreachable!()
+}
+
+/// Add the linked worktree, with a detached HEAD at the current HEAD commit.
+pub fn create_worktree(repository: &Repository) -> Result<PathBuf, RebaseError> {
+    let (name, path) = free_worktree_name(repository)?;
+    let head = repository.head()?.peel_to_commit()?;
+
+    info!("creating worktree {:?}", path);
+    // this creates a branch of the same name, we don't want it.
+    repository.worktree(&name, &path, None)?;
+    let worktree_repository = Repository::open(&path)?;
+    worktree_repository.set_head_detached(head.id())?;
+    repository.find_branch(&name, git2::BranchType::Local)?.delete()?;
+
+    Ok(path)
+}
+
+/// Whether @repository is opened in the worktree at @path.
+pub fn is_worktree_at(repository: &Repository, path: &Path) -> bool {
+    match (repository.workdir().map(Path::canonicalize), path.canonicalize()) {
+        (Some(Ok(workdir)), Ok(path)) => workdir == path,
+        _ => false,
+    }
+}
+
+/// Delete the worktree, both the files and its administrative data.
+pub fn remove_worktree(path: &Path) -> Result<(), RebaseError> {
+    info!("removing worktree {:?}", path);
+    let worktree = Worktree::open_from_repository(&Repository::open(path)?)?;
+    worktree.prune(Some(WorktreePruneOptions::new().valid(true).working_tree(true)))?;
+    Ok(())
+}
@@ -0,0 +1,520 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// Sharing the segment & sum definitions through a remote.  Git does not
+// transfer symbolic refs, so they travel as blobs:
+//
+//   refs/hierarchy/heads/<name>   the head commit (segments & sums)
+//   refs/hierarchy/start/<name>   the start commit of a segment
+//   refs/hierarchy/base/<name>    blob: the full ref name of the base
+//   refs/hierarchy/sums/<name>    blob: the full ref names of the summands, one per line
+//
+// `fetch-definitions' copies them to refs/remote-hierarchy/<remote>/ and
+// re-creates the missing segments & sums from there.
+
+use git2::{ErrorCode, Oid, PushOptions, RemoteCallbacks, Repository};
+
+use std::cell::RefCell;
+use std::fmt;
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::git_hierarchy::{base_name, head_name, optional_id, segments, start_name, sum_summands,
+                            summand_ref_name, sums};
+use crate::rebase::RebaseError;
+use crate::utils::{concatenate, extract_name, short};
+
+// on the remote.
+const PUBLISHED_PREFIX: &str = "refs/hierarchy/";
+// the blobs must be in refs to be pushed.  Only during `publish'.
+const STAGING_PREFIX: &str = "refs/hierarchy-publish/";
+// + <remote>/
+const FETCHED_PREFIX: &str = "refs/remote-hierarchy/";
+// + <remote>/, until the fetch succeeds.
+const FETCHING_PREFIX: &str = "refs/remote-hierarchy-fetch/";
+
+const SHARE_REFLOG: &str = "hierarchy: fetch-definitions";
+
+/// A segment or sum, as the refs define it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Definition {
+    Segment { name: String, base: String, start: Oid, head: Oid },
+    /// full ref names, in summand order.
+    Sum { name: String, summands: Vec<String>, head: Oid },
+}
+
+impl Definition {
+    pub fn name(&self) -> &str {
+        match self {
+            Definition::Segment { name, .. } | Definition::Sum { name, .. } => name,
+        }
+    }
+
+    pub fn head(&self) -> Oid {
+        match self {
+            Definition::Segment { head, .. } | Definition::Sum { head, .. } => *head,
+        }
+    }
+}
+
+/// The local definition of @name (short), None if it's neither segment nor sum.
+pub fn local_definition(repository: &Repository, name: &str) -> Result<Option<Definition>, RebaseError> {
+    let broken = || RebaseError::WrongHierarchy(name.to_owned());
+
+    match repository.find_reference(&base_name(name)) {
+        Ok(base) => {
+            return Ok(Some(Definition::Segment {
+                name: name.to_owned(),
+                base: base.symbolic_target().ok_or_else(broken)?.to_owned(),
+                start: repository.refname_to_id(&start_name(name))?,
+                head: repository.refname_to_id(&head_name(name))?,
+            }));
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let summands = sum_summands(repository, name);
+    if summands.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Definition::Sum {
+        name: name.to_owned(),
+        summands: summands.iter()
+            .map(|s| s.symbolic_target().map(str::to_owned).ok_or_else(broken))
+            .collect::<Result<_, _>>()?,
+        head: repository.refname_to_id(&head_name(name))?,
+    }))
+}
+
+/// All the local segments & sums, by name.
+pub fn local_definitions(repository: &Repository) -> Result<Vec<Definition>, RebaseError> {
+    let mut names: Vec<String> = segments(repository).chain(sums(repository)).collect();
+    names.sort();
+    names.dedup();
+
+    let mut definitions = Vec::new();
+    for name in names {
+        definitions.extend(local_definition(repository, &name)?);
+    }
+    Ok(definitions)
+}
+
+// the @content as a blob under the staging ref @name.
+fn stage_blob(repository: &Repository, name: &str, content: &str) -> Result<(), RebaseError> {
+    let oid = repository.blob(content.as_bytes())?;
+    repository.reference(name, oid, true, "hierarchy: publish")?;
+    Ok(())
+}
+
+// all the refs under @prefix.
+fn delete_references(repository: &Repository, prefix: &str) -> Result<(), RebaseError> {
+    for reference in repository.references_glob(&concatenate(prefix, "*"))? {
+        reference?.delete()?;
+    }
+    Ok(())
+}
+
+/// How a local definition relates to the one published on the remote.
+#[derive(Debug, PartialEq)]
+pub enum Published {
+    /// not there yet.
+    New,
+    /// the same definition, the head moves forward.
+    Updated,
+    Same,
+    /// the same definition, the remote head is ahead of the local one and stays.
+    Behind,
+    Conflict(Vec<String>),
+}
+
+impl Published {
+    pub fn conflict(&self) -> bool {
+        matches!(self, Published::Conflict(_))
+    }
+}
+
+impl fmt::Display for Published {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Published::New => write!(f, "new"),
+            Published::Updated => write!(f, "updated"),
+            Published::Same => write!(f, "up-to-date"),
+            Published::Behind => write!(f, "the remote head is ahead, kept"),
+            Published::Conflict(differences) => write!(f, "conflict: {}", differences.join(", ")),
+        }
+    }
+}
+
+fn compare_published(repository: &Repository, local: &Definition, remote: Option<&Definition>)
+                     -> Result<Published, RebaseError> {
+    let Some(remote) = remote else {
+        return Ok(Published::New);
+    };
+    let differences = differences(local, remote);
+    if !differences.is_empty() {
+        return Ok(Published::Conflict(differences));
+    }
+
+    let (head, remote_head) = (local.head(), remote.head());
+    Ok(if head == remote_head {
+        Published::Same
+    } else if repository.graph_descendant_of(head, remote_head)? {
+        Published::Updated
+    } else if repository.graph_descendant_of(remote_head, head)? {
+        Published::Behind
+    } else {
+        Published::Conflict(vec![format!("the head is at {} here, at {} there, diverged",
+                                         short(head), short(remote_head))])
+    })
+}
+
+/// Push the local definitions to @remote_name, never overwriting what others
+/// published there: the remote definitions are fetched first, and if any differs
+/// nothing is pushed.  Definitions only on the remote stay there.
+pub fn publish(repository: &Repository, remote_name: &str) -> Result<Vec<(String, Published)>, RebaseError> {
+    let remote_definitions = fetch_definitions(repository, remote_name)?;
+    let definitions = local_definitions(repository)?;
+
+    let mut published = Vec::new();
+    for definition in &definitions {
+        let remote = remote_definitions.iter().find(|r| r.name() == definition.name());
+        published.push((definition, compare_published(repository, definition, remote)?));
+    }
+    let by_name = |published: Vec<(&Definition, Published)>| {
+        published.into_iter().map(|(d, p)| (d.name().to_owned(), p)).collect()
+    };
+    if published.iter().any(|(_, p)| p.conflict()) {
+        warn!("definitions differ on {}, not publishing", remote_name);
+        return Ok(by_name(published));
+    }
+
+    // without `+': a concurrent change there makes the push fail.
+    let mut refspecs = Vec::new();
+    let mut push = |local: &str, kind: &str, name: &str| {
+        refspecs.push(format!("{}:{}{}/{}", local, PUBLISHED_PREFIX, kind, name));
+    };
+    let mut staged = Vec::new();
+    for (definition, state) in &published {
+        let name = definition.name();
+        match state {
+            Published::Updated => push(&head_name(name), "heads", name),
+            Published::New => {
+                push(&head_name(name), "heads", name);
+                let (kind, content) = match definition {
+                    Definition::Segment { base, .. } => {
+                        push(&start_name(name), "start", name);
+                        ("base", base.clone() + "\n")
+                    }
+                    Definition::Sum { summands, .. } => {
+                        ("sums", summands.iter().map(|s| s.clone() + "\n").collect())
+                    }
+                };
+                let staging = format!("{}{}/{}", STAGING_PREFIX, kind, name);
+                push(&staging, kind, name);
+                staged.push((staging, content));
+            }
+            _ => {}
+        }
+    }
+
+    let rejected = RefCell::new(Vec::new());
+    let result = (|| -> Result<(), RebaseError> {
+        for (staging, content) in &staged {
+            stage_blob(repository, staging, content)?;
+        }
+        if refspecs.is_empty() {
+            return Ok(());
+        }
+
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(message) = status {
+                warn!("{} rejected: {}", refname, message);
+                rejected.borrow_mut().push(refname.to_owned());
+            }
+            Ok(())
+        });
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+
+        info!("publishing {} refs to {}", refspecs.len(), remote_name);
+        repository.find_remote(remote_name)?.push(&refspecs, Some(&mut push_options))?;
+        Ok(())
+    })();
+
+    // also those staged before a failure.
+    delete_references(repository, STAGING_PREFIX)?;
+    result?;
+
+    let rejected = rejected.take();
+    if !rejected.is_empty() {
+        return Err(RebaseError::PushRefused(rejected));
+    }
+    Ok(by_name(published))
+}
+
+fn fetched_prefix(remote_name: &str) -> String {
+    format!("{}{}/", FETCHED_PREFIX, remote_name)
+}
+
+// the blob the ref @name points at, as lines.
+fn blob_lines(repository: &Repository, name: &str) -> Result<Vec<String>, RebaseError> {
+    let blob = repository.find_reference(name)?.peel_to_blob()?;
+    let content = std::str::from_utf8(blob.content())
+        .map_err(|_| RebaseError::WrongHierarchy(name.to_owned()))?;
+    Ok(content.lines().filter(|l| !l.is_empty()).map(str::to_owned).collect())
+}
+
+/// The definitions under @prefix, laid out as on the remote.
+fn read_definitions(repository: &Repository, prefix: &str) -> Result<Vec<Definition>, RebaseError> {
+    let names_under = |kind: &str| -> Result<Vec<String>, RebaseError> {
+        let kind_prefix = format!("{}{}/", prefix, kind);
+        let mut names = Vec::new();
+        for reference in repository.references_glob(&concatenate(&kind_prefix, "*"))? {
+            let reference = reference?;
+            names.push(reference.name().unwrap().strip_prefix(&kind_prefix).unwrap().to_owned());
+        }
+        names.sort();
+        Ok(names)
+    };
+    let head = |name: &str| optional_id(repository, &format!("{}heads/{}", prefix, name));
+
+    let mut definitions = Vec::new();
+    for name in names_under("base")? {
+        let start = optional_id(repository, &format!("{}start/{}", prefix, name))?;
+        let (Some(start), Some(head)) = (start, head(&name)?) else {
+            warn!("incomplete remote segment {}, skipping", name);
+            continue;
+        };
+        let [base] = <[String; 1]>::try_from(blob_lines(repository, &format!("{}base/{}", prefix, name))?)
+            .map_err(|_| RebaseError::WrongHierarchy(name.clone()))?;
+        definitions.push(Definition::Segment { name, base, start, head });
+    }
+    for name in names_under("sums")? {
+        let Some(head) = head(&name)? else {
+            warn!("incomplete remote sum {}, skipping", name);
+            continue;
+        };
+        let summands = blob_lines(repository, &format!("{}sums/{}", prefix, name))?;
+        definitions.push(Definition::Sum { name, summands, head });
+    }
+    Ok(definitions)
+}
+
+/// Fetch the definitions published to @remote_name, replacing the previous copy.
+/// If the fetch fails, the previous copy stays.
+pub fn fetch_definitions(repository: &Repository, remote_name: &str) -> Result<Vec<Definition>, RebaseError> {
+    let prefix = fetched_prefix(remote_name);
+    let fetching = format!("{}{}/", FETCHING_PREFIX, remote_name);
+    let mut remote = repository.find_remote(remote_name)?;
+
+    // left by an interrupted fetch.
+    delete_references(repository, &fetching)?;
+
+    let refspec = format!("+{}*:{}*", PUBLISHED_PREFIX, fetching);
+    info!("fetch {} {}", remote_name, refspec);
+    if let Err(e) = remote.fetch(&[&refspec], None, Some("fetch hierarchy definitions")) {
+        delete_references(repository, &fetching)?;
+        return Err(e.into());
+    }
+
+    // so that definitions deleted there go away here.
+    delete_references(repository, &prefix)?;
+    let mut names = Vec::new();
+    for reference in repository.references_glob(&concatenate(&fetching, "*"))? {
+        names.push(reference?.name().unwrap().to_owned());
+    }
+    for name in names {
+        let target = concatenate(&prefix, name.strip_prefix(&fetching).unwrap());
+        repository.find_reference(&name)?.rename(&target, true, SHARE_REFLOG)?;
+    }
+
+    read_definitions(repository, &prefix)
+}
+
+/// How a remote definition relates to the local one.
+#[derive(Debug, PartialEq)]
+pub enum Reconciled {
+    Created,
+    Same,
+    /// the same definition, the local branch is elsewhere and stays there.
+    HeadDiffers { local: Oid, remote: Oid },
+    /// nothing changed locally.
+    Conflict(Vec<String>),
+}
+
+impl Reconciled {
+    pub fn conflict(&self) -> bool {
+        matches!(self, Reconciled::Conflict(_))
+    }
+}
+
+impl fmt::Display for Reconciled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reconciled::Created => write!(f, "created"),
+            Reconciled::Same => write!(f, "up-to-date"),
+            Reconciled::HeadDiffers { local, remote } =>
+                write!(f, "same definition, keeping the local head {} (remote {})", short(*local), short(*remote)),
+            Reconciled::Conflict(differences) => write!(f, "conflict: {}", differences.join(", ")),
+        }
+    }
+}
+
+// what differs between the @local and the @remote definitions, but the heads.
+fn differences(local: &Definition, remote: &Definition) -> Vec<String> {
+    match (local, remote) {
+        (Definition::Segment { base, start, .. },
+         Definition::Segment { base: remote_base, start: remote_start, .. }) => {
+            let mut differences = Vec::new();
+            if base != remote_base {
+                differences.push(format!("based on {} here, on {} there",
+                                         extract_name(base), extract_name(remote_base)));
+            }
+            if start != remote_start {
+                differences.push(format!("starts at {} here, at {} there", short(*start), short(*remote_start)));
+            }
+            differences
+        }
+        (Definition::Sum { summands, .. }, Definition::Sum { summands: remote_summands, .. }) => {
+            if summands == remote_summands {
+                Vec::new()
+            } else {
+                let names = |s: &[String]| s.iter().map(|s| extract_name(s)).collect::<Vec<_>>().join(" + ");
+                vec![format!("sums {} here, {} there", names(summands), names(remote_summands))]
+            }
+        }
+        (Definition::Segment { .. }, Definition::Sum { .. }) => vec!["a segment here, a sum there".to_owned()],
+        (Definition::Sum { .. }, Definition::Segment { .. }) => vec!["a sum here, a segment there".to_owned()],
+    }
+}
+
+fn create(repository: &Repository, definition: &Definition) -> Result<(), RebaseError> {
+    let name = definition.name();
+    info!("creating {} from the remote definition", name);
+    match definition {
+        Definition::Segment { base, start, .. } => {
+            repository.reference(&start_name(name), *start, false, SHARE_REFLOG)?;
+            repository.reference_symbolic(&base_name(name), base, false, SHARE_REFLOG)?;
+        }
+        Definition::Sum { summands, .. } => {
+            for (n, summand) in summands.iter().enumerate() {
+                // numbered from 1.
+                repository.reference_symbolic(&summand_ref_name(name, n + 1), summand, false, SHARE_REFLOG)?;
+            }
+        }
+    }
+    if optional_id(repository, &head_name(name))?.is_none() {
+        repository.reference(&head_name(name), definition.head(), false, SHARE_REFLOG)?;
+    }
+    Ok(())
+}
+
+/// Create the @remote definition locally, if there's none, or compare them.
+pub fn reconcile(repository: &Repository, remote: &Definition) -> Result<Reconciled, RebaseError> {
+    let name = remote.name();
+    match local_definition(repository, name)? {
+        Some(local) => {
+            let differences = differences(&local, remote);
+            Ok(if !differences.is_empty() {
+                Reconciled::Conflict(differences)
+            } else if local.head() != remote.head() {
+                Reconciled::HeadDiffers { local: local.head(), remote: remote.head() }
+            } else {
+                Reconciled::Same
+            })
+        }
+        None => {
+            // a plain branch of that name.
+            if let Some(oid) = optional_id(repository, &head_name(name))?
+                && oid != remote.head()
+            {
+                return Ok(Reconciled::Conflict(vec![
+                    format!("a plain branch at {} here, defined at {} there", short(oid), short(remote.head()))
+                ]));
+            }
+            create(repository, remote)?;
+            Ok(Reconciled::Created)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{TempDir, empty_commit, init, init_bare};
+
+    #[test]
+    fn test_publish_and_fetch() {
+        let dir = TempDir::new("share");
+        init_bare(&dir.join("remote"));
+        let url = dir.join("remote");
+        let url = url.to_str().unwrap();
+
+        let repository = init(&dir.join("local"));
+        repository.remote("origin", url).unwrap();
+        let first = empty_commit(&repository, "first", &[]);
+        let second = empty_commit(&repository, "second", &[first]);
+
+        repository.reference("refs/heads/main", first, true, "test").unwrap();
+        let segment = Definition::Segment {
+            name: "team/feature".to_owned(),
+            base: "refs/heads/main".to_owned(),
+            start: first,
+            head: second,
+        };
+        let sum = Definition::Sum {
+            name: "top".to_owned(),
+            summands: vec!["refs/heads/team/feature".to_owned(), "refs/heads/main".to_owned()],
+            head: second,
+        };
+        create(&repository, &segment).unwrap();
+        create(&repository, &sum).unwrap();
+        assert_eq!(local_definitions(&repository).unwrap(), vec![segment.clone(), sum.clone()]);
+
+        assert_eq!(publish(&repository, "origin").unwrap(),
+                   vec![("team/feature".to_owned(), Published::New), ("top".to_owned(), Published::New)]);
+        // the staging refs are gone.
+        assert_eq!(repository.references_glob(&concatenate(STAGING_PREFIX, "*")).unwrap().count(), 0);
+
+        let other = init(&dir.join("other"));
+        other.remote("origin", url).unwrap();
+        let fetched = fetch_definitions(&other, "origin").unwrap();
+        assert_eq!(fetched, vec![segment.clone(), sum.clone()]);
+        assert_eq!(other.references_glob(&concatenate(FETCHING_PREFIX, "*")).unwrap().count(), 0);
+
+        assert_eq!(reconcile(&other, &segment).unwrap(), Reconciled::Created);
+        assert_eq!(reconcile(&other, &sum).unwrap(), Reconciled::Created);
+        assert_eq!(local_definitions(&other).unwrap(), fetched);
+        assert_eq!(reconcile(&other, &segment).unwrap(), Reconciled::Same);
+
+        other.reference("refs/heads/team/feature", first, true, "test").unwrap();
+        assert_eq!(reconcile(&other, &segment).unwrap(),
+                   Reconciled::HeadDiffers { local: first, remote: second });
+
+        other.reference_symbolic("refs/sums/top/2", "refs/heads/other", true, "test").unwrap();
+        assert!(reconcile(&other, &sum).unwrap().conflict());
+
+        // nothing is pushed over the published definitions.
+        let published = publish(&other, "origin").unwrap();
+        assert_eq!(published[0], ("team/feature".to_owned(), Published::Behind));
+        assert!(published[1].1.conflict());
+        assert_eq!(fetch_definitions(&other, "origin").unwrap(), vec![segment.clone(), sum.clone()]);
+
+        let third = empty_commit(&repository, "third", &[second]);
+        repository.reference("refs/heads/team/feature", third, true, "test").unwrap();
+        assert_eq!(publish(&repository, "origin").unwrap(),
+                   vec![("team/feature".to_owned(), Published::Updated), ("top".to_owned(), Published::Same)]);
+
+        // not a segment here.
+        let renamed = Definition::Segment {
+            name: "main".to_owned(),
+            base: "refs/heads/team/feature".to_owned(),
+            start: first,
+            head: second,
+        };
+        other.reference("refs/heads/main", first, true, "test").unwrap();
+        assert!(reconcile(&other, &renamed).unwrap().conflict());
+    }
+}
@@ -0,0 +1,189 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// The refs a poset rebase is going to move, as they were before it started.
+// `git-rebase-poset --abort' puts them back.  Kept inside the `RebaseState'.
+
+use git2::{Error, Oid, Repository, build::CheckoutBuilder};
+use serde::{Deserialize, Serialize};
+
+#[allow(unused)]
+use tracing::{debug, info, warn};
+
+use crate::base::checkout_detached;
+use crate::git_hierarchy::GitHierarchy;
+use crate::utils::serde_oid;
+
+const ABORT_REFLOG: &str = "poset-rebase: abort";
+
+/// What was checked out when the rebase started.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OriginalHead {
+    Branch(String),
+    Detached(#[serde(with = "serde_oid")] Oid),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRef {
+    pub name: String,
+    #[serde(with = "serde_oid")]
+    pub oid: Oid,
+}
+
+impl OriginalHead {
+    pub fn of(repository: &Repository) -> Result<OriginalHead, Error> {
+        let head = repository.head()?;
+        Ok(if head.is_branch() {
+            OriginalHead::Branch(head.name().unwrap().to_owned())
+        } else {
+            OriginalHead::Detached(head.peel_to_commit()?.id())
+        })
+    }
+}
+
+/// Full ref names and the Oids they pointed at, plus the HEAD.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefSnapshot {
+    pub head: OriginalHead,
+    pub refs: Vec<RecordedRef>,
+}
+
+impl RefSnapshot {
+    /// Collect the refs which rebasing @nodes can move:
+    /// segment heads and their start, sum heads, and local plain branches (fetch fast-forwards them).
+    pub fn record<'repo, 'a>(
+        repository: &'repo Repository,
+        nodes: impl Iterator<Item = &'a GitHierarchy<'repo>>,
+    ) -> Result<RefSnapshot, Error>
+    where
+        'repo: 'a,
+    {
+        let head = OriginalHead::of(repository)?;
+
+        let mut refs = Vec::new();
+        let mut push = |name: &str, oid: Oid| refs.push(RecordedRef { name: name.to_owned(), oid });
+        for node in nodes {
+            match node {
+                GitHierarchy::Name(_) => {
+                    panic!("unresolved node");
+                }
+                GitHierarchy::Segment(segment) => {
+                    let reference = segment.reference.borrow();
+                    push(reference.name().unwrap(), reference.target().unwrap());
+                    push(segment._start.name().unwrap(), segment.start());
+                }
+                GitHierarchy::Sum(sum) => {
+                    let reference = sum.reference.borrow();
+                    push(reference.name().unwrap(), reference.target().unwrap());
+                }
+                GitHierarchy::Reference(reference) => {
+                    if reference.is_branch() {
+                        push(reference.name().unwrap(), reference.peel_to_commit()?.id());
+                    }
+                }
+            }
+        }
+        debug!("recorded {} refs", refs.len());
+        Ok(RefSnapshot { head, refs })
+    }
+
+    /// Move every recorded ref back, and checkout the original HEAD.
+    /// Refs deleted in the meantime are re-created.
+    pub fn restore(&self, repository: &Repository) -> Result<(), Error> {
+        self.restore_refs(repository)?;
+
+        match &self.head {
+            OriginalHead::Branch(name) => repository.set_head(name)?,
+            OriginalHead::Detached(oid) => repository.set_head_detached(*oid)?,
+        }
+
+        let mut checkout_opts = CheckoutBuilder::new();
+        checkout_opts.force();
+        repository.checkout_head(Some(&mut checkout_opts))
+    }
+
+    /// Only the refs, not the HEAD & the checkout.
+    pub fn restore_refs(&self, repository: &Repository) -> Result<(), Error> {
+        for recorded in &self.refs {
+            info!("restoring {} to {}", recorded.name, recorded.oid);
+            repository.reference(&recorded.name, recorded.oid, true, ABORT_REFLOG)?;
+        }
+        Ok(())
+    }
+
+    /// The recorded Oid of the full ref @name.
+    pub fn recorded(&self, name: &str) -> Option<Oid> {
+        self.refs.iter().find(|r| r.name == name).map(|r| r.oid)
+    }
+
+    /// @node still points where it was recorded.  Looked up by its full ref name,
+    /// the node's own name may be the short one given on the command line.
+    pub fn unchanged(&self, node: &GitHierarchy<'_>) -> Result<bool, Error> {
+        let (name, oid) = match node {
+            GitHierarchy::Name(_) => {
+                panic!("unresolved node");
+            }
+            GitHierarchy::Segment(segment) => {
+                let reference = segment.reference.borrow();
+                (reference.name().unwrap().to_owned(), reference.peel_to_commit()?.id())
+            }
+            GitHierarchy::Sum(sum) => {
+                let reference = sum.reference.borrow();
+                (reference.name().unwrap().to_owned(), reference.peel_to_commit()?.id())
+            }
+            GitHierarchy::Reference(reference) => {
+                (reference.name().unwrap().to_owned(), reference.peel_to_commit()?.id())
+            }
+        };
+        Ok(self.recorded(&name) == Some(oid))
+    }
+
+    /// After a successful rebase: checkout the original branch at its new position,
+    /// or the original detached commit.  Keeps local changes.
+    pub fn return_to_head(&self, repository: &Repository) -> Result<(), Error> {
+        match &self.head {
+            OriginalHead::Branch(name) => {
+                let commit = repository.find_reference(name)?.peel_to_commit()?;
+                info!("back to {} at {}", name, commit.id());
+                let mut checkout_opts = CheckoutBuilder::new();
+                checkout_opts.safe();
+                repository.checkout_tree(commit.as_object(), Some(&mut checkout_opts))?;
+                repository.set_head(name)
+            }
+            OriginalHead::Detached(oid) => {
+                info!("back to the detached {}", oid);
+                checkout_detached(repository, &repository.find_commit(*oid)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git_hierarchy::load;
+    use crate::testing::{TempDir, empty_commit, init};
+
+    #[test]
+    fn test_unchanged_short_name() {
+        let dir = TempDir::new("snapshot");
+        let repository = init(dir.path());
+        let first = empty_commit(&repository, "first", &[]);
+        let second = empty_commit(&repository, "second", &[first]);
+        repository.reference("refs/heads/a", first, false, "test").unwrap();
+        repository.reference("refs/heads/b", second, false, "test").unwrap();
+        repository.reference("refs/heads/top", second, false, "test").unwrap();
+        repository.reference_symbolic("refs/sums/top/1", "refs/heads/a", false, "test").unwrap();
+        repository.reference_symbolic("refs/sums/top/2", "refs/heads/b", false, "test").unwrap();
+        repository.set_head("refs/heads/top").unwrap();
+
+        // as given on the command line.
+        let top = load(&repository, "top").unwrap();
+        assert!(matches!(top, GitHierarchy::Sum(_)));
+        let snapshot = RefSnapshot::record(&repository, std::iter::once(&top)).unwrap();
+        assert!(snapshot.unchanged(&top).unwrap());
+
+        repository.reference("refs/heads/top", first, true, "test").unwrap();
+        assert!(!snapshot.unchanged(&load(&repository, "top").unwrap()).unwrap());
+    }
+}
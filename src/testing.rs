@@ -0,0 +1,61 @@
+#![deny(elided_lifetimes_in_paths)]
+
+// Scratch repositories for the tests.  The directory goes away when the
+// `TempDir' is dropped, also when an assert fails.
+
+use git2::{Oid, Repository, Signature};
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// unique per test, even within one process.
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("git-hierarchy-{}-{}-{}",
+                                                     name, std::process::id(),
+                                                     COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// with a user, so merges & co. can sign.
+pub(crate) fn init(path: &Path) -> Repository {
+    let repository = Repository::init(path).unwrap();
+    let mut config = repository.config().unwrap();
+    config.set_str("user.name", "t").unwrap();
+    config.set_str("user.email", "t@t").unwrap();
+    repository
+}
+
+pub(crate) fn init_bare(path: &Path) -> Repository {
+    Repository::init_bare(path).unwrap()
+}
+
+/// A commit of the empty tree, not on any ref.
+pub(crate) fn empty_commit(repository: &Repository, message: &str, parents: &[Oid]) -> Oid {
+    let signature = Signature::now("t", "t@t").unwrap();
+    let tree = repository.find_tree(repository.treebuilder(None).unwrap().write().unwrap()).unwrap();
+    let parents = parents.iter().map(|oid| repository.find_commit(*oid).unwrap()).collect::<Vec<_>>();
+    let parents = parents.iter().collect::<Vec<_>>();
+    repository.commit(None, &signature, &signature, message, &tree, &parents).unwrap()
+}
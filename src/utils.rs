@@ -16,6 +16,11 @@ pub fn extract_name(refname: &str) -> &str {
     a
 }
 
+/// the abbreviated oid, for messages.
+pub fn short(oid: git2::Oid) -> String {
+    oid.to_string()[..7].to_owned()
+}
+
 // Return: iter2 - hash(iter1)
 pub fn iterator_difference<T, U, I1, I2>(iter1: I1, iter2: I2) -> impl Iterator<Item = U>
 where
@@ -58,6 +63,40 @@ where
 }
 
 
+/// (De)serialize a git2::Oid as its hex string: `#[serde(with = "serde_oid")]`
+pub mod serde_oid {
+    use git2::Oid;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(oid: &Oid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(oid)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Oid, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Oid::from_str(&s).map_err(D::Error::custom)
+    }
+
+    /// the same for `Option<Oid>`, together with `#[serde(default)]`
+    pub mod option {
+        use git2::Oid;
+        use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+        pub fn serialize<S: Serializer>(oid: &Option<Oid>, serializer: S) -> Result<S::Ok, S::Error> {
+            match oid {
+                Some(oid) => serializer.collect_str(oid),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Oid>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|s| Oid::from_str(&s).map_err(D::Error::custom))
+                .transpose()
+        }
+    }
+}
+
 pub fn init_tracing(verbose: u8) {
     if let Ok(rust_log) = std::env::var("RUST_LOG") {
         tracing::subscriber::set_global_default(